@@ -0,0 +1,153 @@
+use super::types::{Cents, Seconds};
+use super::temperament::{TuningData, cents};
+
+/// 5-limit ratios for each interval class above the root.
+const RATIOS: [f64; 12] = [
+	1.     , 16./15.,
+	9./8.  ,  6./5. ,
+	5./4.  ,
+	4./3.  , 45./32.,
+	3./2.  ,  8./5. ,
+	5./3.  ,  9./5. ,
+	15./8.
+];
+const NUMERATORS:   [f64; 12] = [1., 16., 9., 6., 5., 4., 45., 3., 8., 5., 9., 15.];
+const DENOMINATORS: [f64; 12] = [1., 15., 8., 5., 4., 3., 32., 2., 5., 3., 5., 8.];
+
+/// Retunes whatever is held to small-integer ratios above a root, which
+/// is either fixed or picked as the most consonant reading of the chord.
+///
+/// Each chord is tuned from the root's pitch in the underlying table plus
+/// an anchor offset. The anchor is chosen so that a note held over from
+/// the last chord keeps its pitch, and is clamped to `max_drift` so that a
+/// long chain of common tones cannot walk the whole instrument away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveTuning {
+	pub root: Option<u8>,
+	pub glide: Seconds,
+	pub max_drift: Cents,
+	anchor: Cents,
+}
+
+impl AdaptiveTuning {
+	pub fn new() -> AdaptiveTuning {
+		AdaptiveTuning {
+			root: None,
+			glide: 0.05,
+			max_drift: 25.,
+			anchor: 0.,
+		}
+	}
+	pub fn anchor(&self) -> Cents {
+		self.anchor
+	}
+	/// Tenney height of the interval from `root` up to `key`.
+	fn complexity(root: u8, key: i8) -> f64 {
+		let ic = (key as i32 - root as i32).rem_euclid(12) as usize;
+		(NUMERATORS[ic] * DENOMINATORS[ic]).log2()
+	}
+	/// Picks the pitch class that makes the held keys simplest to express.
+	/// Ties go to the lowest key.
+	pub fn detect_root(keys: &[i8]) -> Option<u8> {
+		let mut sorted = keys.to_vec();
+		sorted.sort();
+		let mut best: Option<(f64, u8)> = None;
+		for &candidate in sorted.iter() {
+			let pc = (candidate as i32).rem_euclid(12) as u8;
+			let score: f64 = keys.iter().map(|&k| Self::complexity(pc, k)).sum();
+			match best {
+				Some((s, _)) if s <= score => {},
+				_ => best = Some((score, pc)),
+			}
+		}
+		best.map(|(_, pc)| pc)
+	}
+	/// Returns the offset from `tuning` for each of `keys`. `current` holds
+	/// the offset a voice is already sounding at, or `None` for a new note.
+	pub fn retune(&mut self, tuning: &TuningData, keys: &[i8], current: &[Option<Cents>]) -> Vec<Cents> {
+		if keys.is_empty() {
+			return vec![];
+		}
+		let pc = match self.root.or_else(|| Self::detect_root(keys)) {
+			Some(pc) => pc as i32,
+			None => return vec![],
+		};
+		let lowest = *keys.iter().min().unwrap() as i32;
+		let root = lowest - (lowest - pc).rem_euclid(12);
+		let base = if root < 0 {
+			tuning.lookup((root + 12) as i8) / 2.
+		} else {
+			tuning.lookup(root as i8)
+		};
+
+		let targets: Vec<Cents> = keys.iter().map(|&k| {
+			let interval = k as i32 - root;
+			let ratio = RATIOS[(interval % 12) as usize] * (2.0_f64).powi(interval / 12);
+			cents(tuning.lookup(k), base * ratio)
+		}).collect();
+
+		let held = targets.iter().zip(current.iter())
+			.filter_map(|(t, c)| c.map(|c| c - t))
+			.next();
+		if let Some(anchor) = held {
+			self.anchor = anchor.max(-self.max_drift).min(self.max_drift);
+		}
+		targets.iter().map(|t| t + self.anchor).collect()
+	}
+}
+
+#[cfg(test)]
+fn close(a: Cents, b: Cents) -> bool {
+	(a - b).abs() < 0.01
+}
+
+#[test]
+fn test_detect_root() {
+	assert_eq!(AdaptiveTuning::detect_root(&[60, 64, 67]), Some(0));
+	assert_eq!(AdaptiveTuning::detect_root(&[64, 67, 72]), Some(0));
+	assert_eq!(AdaptiveTuning::detect_root(&[57, 60, 64]), Some(9));
+	assert_eq!(AdaptiveTuning::detect_root(&[]), None);
+}
+
+#[test]
+fn test_adaptive_triads() {
+	use super::temperament::{TUNINGS, Tuning};
+	let equal = &TUNINGS[Tuning::EquaTemp];
+	let mut adaptive = AdaptiveTuning::new();
+
+	let offsets = adaptive.retune(equal, &[64, 67, 72], &[None, None, None]);
+	assert!(close(offsets[0], -13.686), "{:?}", offsets);
+	assert!(close(offsets[1], 1.955), "{:?}", offsets);
+	assert!(close(offsets[2], 0.), "{:?}", offsets);
+
+	// A minor keeps the held E where it was
+	let offsets = adaptive.retune(equal, &[57, 60, 64], &[None, None, Some(-13.686)]);
+	assert!(close(offsets[2], -13.686), "{:?}", offsets);
+	assert!(close(adaptive.anchor(), -15.641), "{:?}", adaptive.anchor());
+	assert!(close(offsets[1] - offsets[0], 15.641), "{:?}", offsets);
+
+	adaptive.root = Some(0);
+	let offsets = adaptive.retune(equal, &[64, 67], &[None, None]);
+	assert!(close(offsets[0] - adaptive.anchor(), -13.686), "{:?}", offsets);
+	assert!(adaptive.retune(equal, &[], &[]).is_empty());
+}
+
+#[test]
+fn test_adaptive_drift() {
+	use super::temperament::{TUNINGS, Tuning};
+	let equal = &TUNINGS[Tuning::EquaTemp];
+	let mut adaptive = AdaptiveTuning::new();
+	adaptive.max_drift = 20.;
+	// C-Am-F-Dm-G-C walks down a syntonic comma if left unchecked
+	let chords: [&[i8]; 6] = [&[60, 64, 67], &[60, 64, 69], &[60, 65, 69], &[62, 65, 69], &[62, 67, 71], &[60, 64, 67]];
+	let mut previous: Vec<(i8, Cents)> = vec![];
+	for chord in chords.iter() {
+		let current: Vec<Option<Cents>> = chord.iter().map(|k| {
+			previous.iter().find(|(p, _)| p == k).map(|(_, c)| *c)
+		}).collect();
+		let offsets = adaptive.retune(equal, chord, &current);
+		assert!(adaptive.anchor().abs() <= 20.);
+		previous = chord.iter().cloned().zip(offsets.into_iter()).collect();
+	}
+	assert_eq!(adaptive.anchor(), -20.);
+}
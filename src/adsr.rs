@@ -1,3 +1,5 @@
+use serde::{Serialize, Deserialize};
+
 use super::types::{SampleRated, SampleRate, Sample, Frequency, Seconds};
 
 //const SAMPLE_RATE: u64 = 96000;
@@ -9,9 +11,80 @@ pub trait Gate {
 	fn gate_close(&mut self);
 }
 
+const CURVATURE: f64 = 5.0;
+/// How long `Retrigger::Reset` takes to fade a sounding envelope out.
+pub const FADE_TIME: Seconds = 0.002;
+
+/// What `gate_open` does to an envelope that is still sounding.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Retrigger {
+	/// Fades out over a couple of milliseconds, then attacks from zero.
+	Reset,
+	/// Attacks from wherever the level is now.
+	Continue,
+	/// Leaves a held envelope alone; a releasing one heads back to the
+	/// sustain level without a new attack.
+	Legato,
+}
+
+/// How a segment moves from its start level to its target.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Shape {
+	Linear,
+	/// Moves quickly at first and eases into the target, like a capacitor
+	/// charging or discharging.
+	Exponential,
+	/// The mirror of `Exponential`: starts slowly and speeds up.
+	Logarithmic,
+	/// Continuous curvature; positive values bend towards `Exponential`,
+	/// negative towards `Logarithmic`, and zero is linear.
+	Curvature(f64),
+}
+
+impl Shape {
+	/// Maps the time through a segment, `0..=1`, onto the distance
+	/// travelled towards the target, also `0..=1`.
+	pub fn apply(&self, t: f64) -> f64 {
+		let k = match self {
+			Shape::Linear       => return t,
+			Shape::Exponential  => CURVATURE,
+			Shape::Logarithmic  => -CURVATURE,
+			Shape::Curvature(k) => *k,
+		};
+		if k.abs() < 1e-6 {
+			return t;
+		}
+		(1. - (-k * t).exp()) / (1. - (-k).exp())
+	}
+}
+
+/// Key-follow and velocity sensitivity of an envelope's stage times, both
+/// in octaves of time, so -1 halves them.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeScaling {
+	/// Per octave above `center_key`; negative values make higher notes
+	/// quicker, as on plucked strings and pianos.
+	pub key_follow: f64,
+	pub center_key: i8,
+	/// At full velocity, scaled down linearly for softer notes.
+	pub velocity: f64,
+}
+
+impl TimeScaling {
+	pub fn new() -> TimeScaling {
+		TimeScaling { key_follow: 0., center_key: 60, velocity: 0. }
+	}
+	/// The factor to pass to `scale_times` for a key and a velocity in `0..=1`.
+	pub fn scale(&self, key: i8, velocity: Sample) -> f64 {
+		let octaves = (key as i16 - self.center_key as i16) as f64 / 12.;
+		(2.0 as f64).powf(self.key_follow * octaves + self.velocity * velocity)
+	}
+}
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 enum Stage {
 	Off,
+	Fade,
 	Attack,
 	Decay,
 	Sustain,
@@ -25,7 +98,12 @@ pub struct ADSR {
 	sample_rate: Frequency,
 	clk: u64,
 	a: Seconds, d: Seconds, s: Sample, r: Seconds,
-	da: Frequency, dd: Frequency, dr: Frequency,
+	scale_a: f64, scale_d: f64, scale_r: f64,
+	da: Frequency, dd: Frequency, dr: Frequency, df: Frequency,
+	retrigger: Retrigger,
+	shape_a: Shape, shape_d: Shape, shape_r: Shape,
+	start: Sample,
+	phase: f64,
 }
 
 use Stage::*;
@@ -45,15 +123,32 @@ impl ADSR {
 			sample_rate: 0.,
 			clk: 0,
 			a: 0.1, d: 1.0, s: 0.75, r: 0.25,
-			da: 0., dd: 0., dr: 0.,
+			scale_a: 1., scale_d: 1., scale_r: 1.,
+			da: 0., dd: 0., dr: 0., df: 0.,
+			retrigger: Retrigger::Continue,
+			shape_a: Shape::Linear, shape_d: Shape::Linear, shape_r: Shape::Linear,
+			start: 0.,
+			phase: 0.,
 		};
 		adsr.calc();
 		adsr
 	}
 	pub fn calc(&mut self) {
-		self.da = 1. / (self.a * self.sample_rate);
-		self.dd = 1. / (self.d * self.sample_rate);
-		self.dr = 1. / (self.r * self.sample_rate);
+		self.da = 1. / (self.a * self.scale_a * self.sample_rate);
+		self.dd = 1. / (self.d * self.scale_d * self.sample_rate);
+		self.dr = 1. / (self.r * self.scale_r * self.sample_rate);
+		self.df = 1. / (FADE_TIME * self.sample_rate);
+	}
+	pub fn set_retrigger(&mut self, retrigger: Retrigger) {
+		self.retrigger = retrigger;
+	}
+	/// Stretches (above 1) or shortens (below 1) the stage times of this
+	/// envelope only, e.g. for velocity sensitivity, leaving `set` alone.
+	pub fn scale_times(&mut self, attack: f64, decay: f64, release: f64) {
+		self.scale_a = attack;
+		self.scale_d = decay;
+		self.scale_r = release;
+		self.calc();
 	}
 	pub fn set(&mut self, a: Seconds, d: Seconds, s: Sample, r: Seconds) {
 		if a >= 0.0 { self.a = 15.0 * a.powf(6.0) + 0.01; }
@@ -62,9 +157,39 @@ impl ADSR {
 		if r >= 0.0 { self.r = 15.0 * r.powf(6.0) + 0.01; }
 		self.calc();
 	}
+	/// Sets the stage times in seconds, unlike `set`, which maps knob
+	/// positions from 0 to 1 onto them.
+	pub fn set_times(&mut self, a: Seconds, d: Seconds, s: Sample, r: Seconds) {
+		self.a = a.max(0.);
+		self.d = d.max(0.);
+		self.s = s.max(0.).min(1.);
+		self.r = r.max(0.);
+		self.calc();
+	}
+	pub fn set_shapes(&mut self, attack: Shape, decay: Shape, release: Shape) {
+		self.shape_a = attack;
+		self.shape_d = decay;
+		self.shape_r = release;
+	}
 	pub fn value(&self) -> Sample {
 		self.val
 	}
+	fn begin(&mut self, stage: Stage) {
+		self.stage = stage;
+		self.start = self.val;
+		self.phase = 0.;
+	}
+	/// Advances the current segment by `incr` of its length. Returns true
+	/// once the segment is done.
+	fn segment(&mut self, incr: f64, shape: Shape, target: Sample) -> bool {
+		self.phase += incr;
+		if self.phase >= 1. {
+			self.val = target;
+			return true;
+		}
+		self.val = self.start + (target - self.start) * shape.apply(self.phase);
+		false
+	}
 	pub fn is_off(&self) -> bool {
 		self.stage == Off
 	}
@@ -76,26 +201,23 @@ impl ADSR {
 				}
 			},
 			Attack => {
-				self.val += self.da;
-				if self.val >= 1.0 {
-					self.stage = Decay;
-					self.val   = 1.;
+				if self.segment(self.da, self.shape_a, 1.) {
+					self.begin(Decay);
 				}
 			},
 			Decay => {
-				self.val -= self.dd;
-				if self.val <= 0.0 { self.val = 0.0; }
-
-				if self.val < self.s {
-					self.stage = Sustain;
-					self.val   = self.s;
+				if self.segment(self.dd, self.shape_d, self.s) {
+					self.begin(Sustain);
 				}
 			},
 			Release => {
-				self.val -= self.dr;
-				if self.val <= 0.0 {
-					self.val   = 0.0;
-					self.stage = Off;
+				if self.segment(self.dr, self.shape_r, 0.) {
+					self.begin(Off);
+				}
+			},
+			Fade => {
+				if self.segment(self.df, Shape::Linear, 0.) {
+					self.begin(Attack);
 				}
 			},
 			Off => {},
@@ -105,11 +227,19 @@ impl ADSR {
 }
 
 impl Gate for ADSR {
+	/// Attack always takes the attack time, however far it has to climb,
+	/// since segments run for their time rather than at a fixed slope.
 	fn gate_open(&mut self) {
-		self.stage = Attack;
+		match (self.retrigger, self.stage) {
+			(_, Off) => self.begin(Attack),
+			(Retrigger::Reset, _) if self.val > 0. => self.begin(Fade),
+			(Retrigger::Legato, Release) => self.begin(Decay),
+			(Retrigger::Legato, _) => {},
+			_ => self.begin(Attack),
+		}
 	}
 	fn gate_close(&mut self) {
-		self.stage = Release;
+		self.begin(Release);
 	}
 }
 
@@ -126,3 +256,98 @@ fn test_adsr() {
 	adsr.gate_close();
 	assert_eq!(adsr.stage, Stage::Release);
 }
+
+#[cfg(test)]
+fn run_for(adsr: &mut ADSR, samples: usize) -> Sample {
+	for _ in 0..samples {
+		adsr.run();
+	}
+	adsr.value()
+}
+
+#[test]
+fn test_adsr_shapes() {
+	// 1 kHz, so stage times of 0.1 s are 100 samples
+	let mut adsr = ADSR::new();
+	adsr.set_sample_rate(1000);
+	adsr.a = 0.1; adsr.d = 0.1; adsr.s = 0.5; adsr.r = 0.1;
+	adsr.calc();
+	adsr.set_shapes(Shape::Linear, Shape::Exponential, Shape::Logarithmic);
+	adsr.gate_open();
+
+	assert!((run_for(&mut adsr, 50) - 0.5).abs() < 1e-9);
+	assert_eq!(run_for(&mut adsr, 50), 1.);
+	assert_eq!(adsr.stage, Stage::Decay);
+	// 1 - 0.5 * 0.924142, the exponential shape half way through
+	assert!((run_for(&mut adsr, 50) - 0.537929).abs() < 1e-6);
+	assert_eq!(run_for(&mut adsr, 50), 0.5);
+	assert_eq!(adsr.stage, Stage::Sustain);
+	assert_eq!(run_for(&mut adsr, 1000), 0.5);
+
+	adsr.gate_close();
+	// 0.5 * (1 - 0.075858), the logarithmic shape half way through
+	assert!((run_for(&mut adsr, 50) - 0.462071).abs() < 1e-6);
+	assert_eq!(run_for(&mut adsr, 50), 0.);
+	assert!(adsr.is_off());
+}
+
+#[test]
+fn test_shape_curvature() {
+	assert_eq!(Shape::Curvature(0.).apply(0.3), 0.3);
+	assert_eq!(Shape::Curvature(CURVATURE).apply(0.3), Shape::Exponential.apply(0.3));
+	for shape in &[Shape::Exponential, Shape::Logarithmic, Shape::Curvature(-2.)] {
+		assert!(shape.apply(0.).abs() < 1e-12);
+		assert!((shape.apply(1.) - 1.).abs() < 1e-12);
+	}
+	assert!(Shape::Curvature(2.).apply(0.5) > 0.5);
+	assert!(Shape::Curvature(-2.).apply(0.5) < 0.5);
+}
+
+#[test]
+fn test_retrigger() {
+	let mut adsr = ADSR::new();
+	adsr.set_sample_rate(1000);
+	adsr.a = 0.1; adsr.d = 0.1; adsr.s = 0.5; adsr.r = 0.1;
+	adsr.calc();
+	adsr.gate_open();
+	run_for(&mut adsr, 300);
+	adsr.gate_close();
+	assert!((run_for(&mut adsr, 50) - 0.25).abs() < 1e-9);
+
+	// continuing climbs from the current level but still takes the attack time
+	let mut cont = adsr;
+	cont.gate_open();
+	assert!((run_for(&mut cont, 50) - 0.625).abs() < 1e-9);
+	assert_eq!(run_for(&mut cont, 50), 1.);
+
+	// resetting fades to zero in 2 ms, then attacks from zero
+	let mut reset = adsr;
+	reset.set_retrigger(Retrigger::Reset);
+	reset.gate_open();
+	assert!((run_for(&mut reset, 1) - 0.125).abs() < 1e-9);
+	assert_eq!(run_for(&mut reset, 1), 0.);
+	assert!((run_for(&mut reset, 50) - 0.5).abs() < 1e-9);
+
+	// legato leaves held notes alone and brings releasing ones back to sustain
+	let mut legato = adsr;
+	legato.set_retrigger(Retrigger::Legato);
+	legato.gate_open();
+	assert_eq!(legato.stage, Stage::Decay);
+	assert_eq!(run_for(&mut legato, 101), 0.5);
+	legato.gate_open();
+	assert_eq!(legato.stage, Stage::Sustain);
+}
+
+#[test]
+fn test_time_scaling() {
+	let scaling = TimeScaling { key_follow: -1., center_key: 60, velocity: -2. };
+	assert_eq!(scaling.scale(60, 0.), 1.);
+	assert_eq!(scaling.scale(72, 0.), 0.5);
+	assert_eq!(scaling.scale(48, 0.), 2.);
+	assert_eq!(scaling.scale(60, 1.), 0.25);
+	assert_eq!(scaling.scale(72, 0.5), 0.25);
+	assert_eq!(TimeScaling::new().scale(127, 1.), 1.);
+	// far apart keys don't overflow
+	let low = TimeScaling { key_follow: 1., center_key: -10, velocity: 0. };
+	assert_eq!(low.scale(127, 0.), (2.0 as f64).powf(137. / 12.));
+}
@@ -0,0 +1,436 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use super::types::{SampleRated, Generator, SampleRate, MidiDispatcher};
+use super::tempo::{Division, CLOCKS_PER_BEAT};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+	Up,
+	Down,
+	UpDown,
+	Random,
+	AsPlayed,
+	Chord,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sync {
+	Internal,
+	MidiClock,
+}
+
+type Key = (u8, u8);
+
+/// Sits in front of a voice engine and turns held notes into a rhythmic
+/// pattern. Time only moves when `generate` is called, one sample at a
+/// time, so a pattern can be checked offline by interleaving MIDI events
+/// with calls to `generate`.
+pub struct Arpeggiator<G> {
+	pub inner: G,
+	enabled: bool,
+	mode: Mode,
+	pub division: Division,
+	octaves: u8,
+	/// Fraction of a step each note is held for.
+	pub gate: f64,
+	/// Fraction of a step that every second step is pushed back by.
+	pub swing: f64,
+	pub bpm: f64,
+	pub sync: Sync,
+	latch: bool,
+
+	held: Vec<Key>,
+	down: [bool; 128],
+	pattern: Vec<Vec<Key>>,
+	sounding: Vec<u8>,
+	channel: u8,
+	index: usize,
+	rng: StdRng,
+
+	sample_rate: f64,
+	clk: u64,
+	start_clk: u64,
+	pos: f64,
+	step: u64,
+	next_at: f64,
+	off_at: f64,
+
+	stopped: bool,
+	ticks: u64,
+	tick_clk: Option<u64>,
+	tick_rate: f64,
+}
+
+impl<G: SampleRated> SampleRated for Arpeggiator<G> {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as f64;
+		self.inner.set_sample_rate(sample_rate);
+	}
+}
+
+impl<G: MidiDispatcher> Arpeggiator<G> {
+	pub fn new(inner: G) -> Arpeggiator<G> {
+		Arpeggiator {
+			inner: inner,
+			enabled: true,
+			mode: Mode::Up,
+			division: Division::Sixteenth,
+			octaves: 1,
+			gate: 0.5,
+			swing: 0.,
+			bpm: 120.,
+			sync: Sync::Internal,
+			latch: false,
+			held: vec![],
+			down: [false; 128],
+			pattern: vec![],
+			sounding: vec![],
+			channel: 0,
+			index: 0,
+			rng: StdRng::seed_from_u64(0),
+			sample_rate: 0.,
+			clk: 0,
+			start_clk: 0,
+			pos: 0.,
+			step: 0,
+			next_at: 0.,
+			off_at: 0.,
+			stopped: false,
+			ticks: 0,
+			tick_clk: None,
+			tick_rate: 0.,
+		}
+	}
+	pub fn seed(&mut self, seed: u64) {
+		self.rng = StdRng::seed_from_u64(seed);
+	}
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+	/// Switching off releases the step sounding and forgets the held keys,
+	/// whose note offs will go straight through to `inner`.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+		if !enabled {
+			self.release();
+			self.held.clear();
+			self.down = [false; 128];
+			self.pattern.clear();
+		}
+	}
+	pub fn mode(&self) -> Mode {
+		self.mode
+	}
+	pub fn set_mode(&mut self, mode: Mode) {
+		self.mode = mode;
+		self.rebuild();
+	}
+	pub fn octaves(&self) -> u8 {
+		self.octaves
+	}
+	pub fn set_octaves(&mut self, octaves: u8) {
+		self.octaves = octaves;
+		self.rebuild();
+	}
+	pub fn latch(&self) -> bool {
+		self.latch
+	}
+	pub fn set_latch(&mut self, latch: bool) {
+		self.latch = latch;
+		if !latch {
+			let down = self.down;
+			self.held.retain(|&(n, _)| down[n as usize]);
+			self.rebuild();
+		}
+	}
+	fn key_down(&mut self, channel: u8, n: u8, v: u8) {
+		if self.latch && !self.down.iter().any(|&d| d) {
+			self.held.clear();
+		}
+		self.channel = channel;
+		self.down[n as usize] = true;
+		if !self.held.iter().any(|&(h, _)| h == n) {
+			self.held.push((n, v));
+		}
+		self.rebuild();
+	}
+	fn key_up(&mut self, n: u8) {
+		self.down[n as usize] = false;
+		if !self.latch {
+			self.held.retain(|&(h, _)| h != n);
+			self.rebuild();
+		}
+	}
+	fn rebuild(&mut self) {
+		let was_empty = self.pattern.is_empty();
+		let mut up: Vec<Key> = vec![];
+		let mut played: Vec<Key> = vec![];
+		let mut sorted = self.held.clone();
+		sorted.sort();
+		for o in 0..self.octaves.max(1) {
+			let shift = |&(n, v): &Key| -> Option<Key> {
+				let n = n as u16 + 12 * o as u16;
+				if n > 127 { None } else { Some((n as u8, v)) }
+			};
+			up.extend(sorted.iter().filter_map(shift));
+			played.extend(self.held.iter().filter_map(shift));
+		}
+		self.pattern = match self.mode {
+			Mode::Up | Mode::Random => up.into_iter().map(|k| vec![k]).collect(),
+			Mode::Down => up.into_iter().rev().map(|k| vec![k]).collect(),
+			Mode::UpDown => {
+				let mut steps = up.clone();
+				if up.len() > 2 {
+					steps.extend(up[1..up.len() - 1].iter().rev());
+				}
+				steps.into_iter().map(|k| vec![k]).collect()
+			},
+			Mode::AsPlayed => played.into_iter().map(|k| vec![k]).collect(),
+			Mode::Chord => (0..self.octaves.max(1)).map(|o| {
+				self.held.iter()
+					.map(|&(n, v)| (n as u16 + 12 * o as u16, v))
+					.filter(|&(n, _)| n <= 127)
+					.map(|(n, v)| (n as u8, v))
+					.collect()
+			}).collect(),
+		};
+		if self.pattern.is_empty() {
+			self.release();
+		} else if was_empty {
+			self.restart();
+		}
+	}
+	/// Lines the first step of a new pattern up with the clock: straight
+	/// away when running freely, or on the next step boundary when synced.
+	fn restart(&mut self) {
+		let len = self.division.beats();
+		self.index = 0;
+		match self.sync {
+			Sync::Internal => {
+				self.start_clk = self.clk;
+				self.pos = 0.;
+				self.step = 0;
+			},
+			Sync::MidiClock => {
+				self.step = (self.pos / len).ceil() as u64;
+			},
+		}
+		self.next_at = self.step_start(self.step);
+	}
+	fn step_start(&self, step: u64) -> f64 {
+		let swing = if step % 2 == 1 { self.swing } else { 0. };
+		(step as f64 + swing) * self.division.beats()
+	}
+	fn release(&mut self) {
+		for n in self.sounding.drain(..) {
+			self.inner.dispatch_midi_in(&midistream::SimpleMsg::note_off(self.channel, n, 0).into());
+		}
+	}
+	fn play_step(&mut self) {
+		self.release();
+		let i = match self.mode {
+			Mode::Random => self.rng.gen_range(0, self.pattern.len()),
+			_ => self.index % self.pattern.len(),
+		};
+		for &(n, v) in self.pattern[i].iter() {
+			self.inner.dispatch_midi_in(&midistream::SimpleMsg::note_on(self.channel, n, v).into());
+			self.sounding.push(n);
+		}
+		self.index += 1;
+		self.off_at = self.next_at + self.gate * self.division.beats();
+		self.step += 1;
+		self.next_at = self.step_start(self.step);
+	}
+	fn clock(&mut self) {
+		if self.stopped {
+			return;
+		}
+		if let Some(last) = self.tick_clk {
+			if self.clk > last {
+				self.tick_rate = 1. / CLOCKS_PER_BEAT / (self.clk - last) as f64;
+			}
+		}
+		self.tick_clk = Some(self.clk);
+		self.pos = self.pos.max(self.ticks as f64 / CLOCKS_PER_BEAT);
+		self.ticks += 1;
+	}
+	fn transport(&mut self, start: bool, stop: bool) {
+		self.stopped = stop;
+		if start {
+			self.ticks = 0;
+			self.pos = 0.;
+			self.tick_clk = None;
+			if !self.pattern.is_empty() {
+				self.restart();
+			}
+		}
+		if stop {
+			self.release();
+		}
+	}
+	fn advance(&mut self) {
+		match self.sync {
+			Sync::Internal => {
+				let elapsed = (self.clk - self.start_clk) as f64;
+				self.pos = elapsed * self.bpm / (60. * self.sample_rate);
+			},
+			Sync::MidiClock => {
+				if !self.stopped {
+					// never run past the next clock that is due
+					let limit = self.ticks as f64 / CLOCKS_PER_BEAT;
+					self.pos = (self.pos + self.tick_rate).min(limit);
+				}
+			},
+		}
+	}
+}
+
+impl<G: Generator + MidiDispatcher> Generator for Arpeggiator<G> {
+	fn generate(&mut self) -> [f32; 2] {
+		if self.enabled {
+			if !self.sounding.is_empty() && self.pos >= self.off_at {
+				self.release();
+			}
+			let running = self.sync == Sync::Internal || !self.stopped;
+			if running && !self.pattern.is_empty() && self.pos >= self.next_at {
+				self.play_step();
+			}
+		}
+		self.clk += 1;
+		self.advance();
+		self.inner.generate()
+	}
+}
+
+impl<G: MidiDispatcher> MidiDispatcher for Arpeggiator<G> {
+	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
+		use midistream::*;
+		if !self.enabled {
+			return self.inner.dispatch_midi_in(msg);
+		}
+		match msg {
+			Msg::Simple(SimpleMsg::NoteOn(y)) if *y.value > 0 => {
+				self.key_down(*y.channel, *y.note, *y.value);
+			},
+			Msg::Simple(SimpleMsg::NoteOn(y)) |
+			Msg::Simple(SimpleMsg::NoteOff(y)) => {
+				self.key_up(*y.note);
+			},
+			Msg::Simple(SimpleMsg::TimingClock) if self.sync == Sync::MidiClock => self.clock(),
+			Msg::Simple(SimpleMsg::Start)       if self.sync == Sync::MidiClock => self.transport(true, false),
+			Msg::Simple(SimpleMsg::Continue)    if self.sync == Sync::MidiClock => self.transport(false, false),
+			Msg::Simple(SimpleMsg::Stop)        if self.sync == Sync::MidiClock => self.transport(false, true),
+			_ => self.inner.dispatch_midi_in(msg),
+		}
+	}
+}
+
+#[cfg(test)]
+struct Recorder {
+	clk: u64,
+	events: Vec<(u64, bool, u8)>,
+}
+
+#[cfg(test)]
+impl Generator for Recorder {
+	fn generate(&mut self) -> [f32; 2] {
+		self.clk += 1;
+		[0., 0.]
+	}
+}
+
+#[cfg(test)]
+impl SampleRated for Recorder {
+	fn set_sample_rate(&mut self, _: SampleRate) {}
+}
+
+#[cfg(test)]
+impl MidiDispatcher for Recorder {
+	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
+		use midistream::*;
+		match msg {
+			Msg::Simple(SimpleMsg::NoteOn(y))  => self.events.push((self.clk, true, *y.note)),
+			Msg::Simple(SimpleMsg::NoteOff(y)) => self.events.push((self.clk, false, *y.note)),
+			_ => {},
+		}
+	}
+}
+
+#[cfg(test)]
+fn arpeggiate(arp: &mut Arpeggiator<Recorder>, samples: u64) -> Vec<(u64, bool, u8)> {
+	for _ in 0..samples {
+		arp.generate();
+	}
+	arp.inner.events.drain(..).collect()
+}
+
+#[test]
+fn test_arpeggiator_up() {
+	use midistream::SimpleMsg;
+	// 120 bpm sixteenths at 1 kHz: one step every 125 samples
+	let mut arp = Arpeggiator::new(Recorder { clk: 0, events: vec![] });
+	arp.set_sample_rate(1000);
+	arp.set_octaves(2);
+	for n in &[67, 60, 64] {
+		arp.dispatch_midi_in(&SimpleMsg::note_on(0, *n, 100).into());
+	}
+	let events = arpeggiate(&mut arp, 750);
+	let ons: Vec<(u64, u8)> = events.iter().filter(|e| e.1).map(|e| (e.0, e.2)).collect();
+	assert_eq!(ons, vec![(0, 60), (125, 64), (250, 67), (375, 72), (500, 76), (625, 79)]);
+	assert!(events.contains(&(62, false, 60)) || events.contains(&(63, false, 60)));
+
+	arp.dispatch_midi_in(&SimpleMsg::note_off(0, 64, 0).into());
+	arp.set_mode(Mode::UpDown);
+	arp.set_latch(true);
+	arp.dispatch_midi_in(&SimpleMsg::note_off(0, 60, 0).into());
+	arp.dispatch_midi_in(&SimpleMsg::note_off(0, 67, 0).into());
+	let events = arpeggiate(&mut arp, 900);
+	let ons: Vec<u8> = events.iter().filter(|e| e.1).map(|e| e.2).collect();
+	assert_eq!(ons, vec![60, 67, 72, 79, 72, 67, 60, 67]);
+
+	// switched off mid step, nothing is left hanging
+	arp.set_enabled(false);
+	let events = arp.inner.events.drain(..).collect::<Vec<_>>();
+	assert_eq!(events.iter().filter(|e| !e.1).count(), 1);
+	assert!(arpeggiate(&mut arp, 1000).is_empty());
+}
+
+#[test]
+fn test_arpeggiator_swing_and_chord() {
+	use midistream::SimpleMsg;
+	let mut arp = Arpeggiator::new(Recorder { clk: 0, events: vec![] });
+	arp.set_sample_rate(1000);
+	arp.set_mode(Mode::Chord);
+	arp.division = Division::Eighth;
+	arp.swing = 0.2;
+	arp.gate = 1.;
+	arp.dispatch_midi_in(&SimpleMsg::note_on(0, 60, 100).into());
+	arp.dispatch_midi_in(&SimpleMsg::note_on(0, 64, 100).into());
+	let events = arpeggiate(&mut arp, 1000);
+	let ons: Vec<u64> = events.iter().filter(|e| e.1 && e.2 == 60).map(|e| e.0).collect();
+	assert_eq!(ons, vec![0, 300, 500, 800]);
+	assert_eq!(events.iter().filter(|e| e.1).count(), 8);
+}
+
+#[test]
+fn test_arpeggiator_midi_clock() {
+	use midistream::SimpleMsg;
+	let mut arp = Arpeggiator::new(Recorder { clk: 0, events: vec![] });
+	arp.set_sample_rate(1000);
+	arp.sync = Sync::MidiClock;
+	arp.dispatch_midi_in(&SimpleMsg::Start.into());
+	arp.dispatch_midi_in(&SimpleMsg::note_on(0, 60, 100).into());
+	let mut ons = vec![];
+	// clocks every 10 samples put a sixteenth at 60 samples
+	for tick in 0..48 {
+		arp.dispatch_midi_in(&SimpleMsg::TimingClock.into());
+		for (clk, on, _) in arpeggiate(&mut arp, 10) {
+			if on { ons.push(clk); }
+		}
+		if tick == 35 {
+			arp.dispatch_midi_in(&SimpleMsg::Stop.into());
+		}
+	}
+	assert_eq!(ons, vec![0, 60, 120, 180, 240, 300]);
+}
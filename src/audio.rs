@@ -75,11 +75,15 @@ impl System {
 				//let mut rng = rand::thread_rng();
 				//Self::write_data(data, channels, &mut next_value)
 				for frame in output.chunks_mut(channels) {
-					let next_sample = generator.generate()[0];
+					let [left, right] = generator.generate();
 					//let next_sample = rng.gen();
-					let value: S = cpal::Sample::from::<f32>(&next_sample);
-					for sample in frame.iter_mut() {
-						*sample = value;
+					if channels == 1 {
+						frame[0] = cpal::Sample::from::<f32>(&((left + right) / 2.));
+						continue;
+					}
+					for (i, sample) in frame.iter_mut().enumerate() {
+						let next_sample = if i % 2 == 0 { left } else { right };
+						*sample = cpal::Sample::from::<f32>(&next_sample);
 					}
 				}
 				while let Some(msg) = rx.try_recv() {
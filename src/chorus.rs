@@ -0,0 +1,220 @@
+use std::f64::consts::PI;
+
+use super::types::{SampleRated, SampleRate, Sample, Seconds, Frequency};
+use super::effects::{Effect, Param, Sweep, clamp};
+use super::delay::Line;
+use super::filter::Svf;
+
+pub const MAX_VOICES: usize = 4;
+const MAX_DELAY: Seconds = 0.05;
+
+/// The three buttons of the Juno-60 chorus: the rate of its triangle LFO
+/// and the range of delays it sweeps.
+const JUNO: [(Frequency, Seconds, Seconds); 3] = [
+	(0.513, 0.00166, 0.00535),
+	(0.863, 0.00166, 0.00535),
+	(9.75, 0.0033, 0.0037),
+];
+/// The bucket brigade chips' filtering, which darkens the wet signal.
+const JUNO_CUTOFF: Frequency = 8000.;
+
+const PARAMS: [Param; 8] = [
+	Param { name: "rate", min: 0.01, max: 10., default: 0.8 },
+	Param { name: "sync", min: 0., max: 1., default: 0. },
+	Param { name: "division", min: 0., max: 11., default: 0. },
+	Param { name: "mode", min: 0., max: 3., default: 0. },
+	Param { name: "voices", min: 1., max: MAX_VOICES as f64, default: 2. },
+	Param { name: "delay", min: 2., max: 30., default: 8. },
+	Param { name: "depth", min: 0., max: 10., default: 3. },
+	Param { name: "spread", min: 0., max: 1., default: 1. },
+];
+
+/// Multi-voice stereo chorus. Each voice is a delay of `delay` to `delay +
+/// depth` milliseconds, swept by a sine spaced evenly in phase from the
+/// others, with `spread` turning the right side's sweeps up to a quarter
+/// cycle away from the left.
+///
+/// Modes 1 to 3 copy the Juno-60's I, II and I+II instead: one line fed
+/// with the mono mix, read on either side by opposite ends of a triangle
+/// at the Juno's own fixed rates and depths.
+pub struct Chorus {
+	sweep: Sweep,
+	mode: usize,
+	voices: usize,
+	delay: Seconds,
+	depth: Seconds,
+	spread: f64,
+	lines: Vec<Line>,
+	lowpass: [Svf; 2],
+	sample_rate: f64,
+}
+
+impl Chorus {
+	pub fn new() -> Chorus {
+		let mut sweep = Sweep::new(PARAMS[0].default);
+		sweep.division = PARAMS[2].default as usize;
+		Chorus {
+			sweep,
+			mode: PARAMS[3].default as usize,
+			voices: PARAMS[4].default as usize,
+			delay: PARAMS[5].default / 1000.,
+			depth: PARAMS[6].default / 1000.,
+			spread: PARAMS[7].default,
+			lines: (0..2).map(|_| Line::new()).collect(),
+			lowpass: [Svf::new(); 2],
+			sample_rate: 0.,
+		}
+	}
+	fn juno(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		let (rate, min, max) = JUNO[self.mode - 1];
+		let phase = self.sweep.run(rate);
+		let triangle = 1. - 4. * (phase - 0.5).abs();
+		let centre = (min + max) / 2. * self.sample_rate;
+		let swing = (max - min) / 2. * self.sample_rate * triangle;
+		let line = &mut self.lines[0];
+		let out = [line.read(centre + swing), line.read(centre - swing)];
+		line.write((input[0] + input[1]) / 2.);
+		[self.lowpass[0].process(out[0]), self.lowpass[1].process(out[1])]
+	}
+}
+
+impl SampleRated for Chorus {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as f64;
+		let len = (MAX_DELAY * self.sample_rate).ceil() as usize + 2;
+		for line in self.lines.iter_mut() {
+			line.resize(len);
+		}
+		for lowpass in self.lowpass.iter_mut() {
+			lowpass.set_sample_rate(sample_rate);
+			lowpass.set(JUNO_CUTOFF, 0.);
+		}
+		self.sweep.set_sample_rate(sample_rate);
+	}
+}
+
+impl Effect for Chorus {
+	fn name(&self) -> &'static str {
+		"Chorus"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		if self.lines[0].is_empty() {
+			return [0.; 2];
+		}
+		if self.mode > 0 {
+			return self.juno(input);
+		}
+		let phase = self.sweep.run(self.sweep.frequency());
+		let delay = self.delay * self.sample_rate;
+		let depth = self.depth * self.sample_rate;
+		let mut out = [0.; 2];
+		for c in 0..2 {
+			let offset = c as f64 * self.spread / 4.;
+			for v in 0..self.voices {
+				let swing = (2. * PI * (phase + offset + v as f64 / self.voices as f64)).sin();
+				out[c] += self.lines[c].read(delay + depth * (1. + swing) / 2.);
+			}
+			out[c] /= self.voices as Sample;
+			self.lines[c].write(input[c]);
+		}
+		out
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0..=2 => self.sweep.get(param),
+			3 => self.mode as f64,
+			4 => self.voices as f64,
+			5 => self.delay * 1000.,
+			6 => self.depth * 1000.,
+			7 => self.spread,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		let value = clamp(&PARAMS, param, value);
+		match param {
+			0..=2 => self.sweep.set(param, value),
+			3 => self.mode = value.round() as usize,
+			4 => self.voices = value.round() as usize,
+			5 => self.delay = value / 1000.,
+			6 => self.depth = value / 1000.,
+			_ => self.spread = value,
+		}
+	}
+	fn reset(&mut self) {
+		for line in self.lines.iter_mut() {
+			line.clear();
+		}
+		for lowpass in self.lowpass.iter_mut() {
+			lowpass.reset();
+		}
+		self.sweep.reset();
+	}
+	fn set_bpm(&mut self, bpm: f64) {
+		self.sweep.bpm = bpm;
+	}
+}
+
+/// Feeds a ramp through, so that each output sample gives away how far
+/// back it was read.
+#[cfg(test)]
+fn ramp(chorus: &mut Chorus, samples: usize) -> Vec<[Sample; 2]> {
+	(0..samples).map(|n| n as Sample).map(|n| {
+		let [l, r] = chorus.process([n, n]);
+		[n - l, n - r]
+	}).collect()
+}
+
+#[test]
+fn test_chorus() {
+	let mut chorus = Chorus::new();
+	chorus.set(chorus.find("voices").unwrap(), 1.);
+	chorus.set(chorus.find("rate").unwrap(), 1.);
+	chorus.set_sample_rate(1000);
+	let delays = ramp(&mut chorus, 2000);
+	// one voice sweeps from 8 to 11 ms, the right a quarter cycle behind
+	for d in delays[100..].iter() {
+		assert!(d[0] >= 8. - 1e-9 && d[0] <= 11. + 1e-9);
+	}
+	assert!((delays[1000][0] - 9.5).abs() < 1e-9);
+	assert!((delays[1250][0] - 11.).abs() < 1e-9);
+	assert!((delays[1000][1] - 11.).abs() < 1e-9);
+
+	// voices spread evenly around the cycle average out to the middle
+	chorus.reset();
+	chorus.set(chorus.find("voices").unwrap(), 4.);
+	let delays = ramp(&mut chorus, 2000);
+	assert!(delays[100..].iter().all(|d| (d[0] - 9.5).abs() < 1e-9));
+
+	chorus.set(chorus.find("sync").unwrap(), 1.);
+	chorus.set(chorus.find("division").unwrap(), 2.);
+	assert_eq!(chorus.sweep.frequency(), 2.);
+	chorus.set_bpm(60.);
+	assert_eq!(chorus.sweep.frequency(), 1.);
+}
+
+#[test]
+fn test_juno_chorus() {
+	let mut chorus = Chorus::new();
+	chorus.set_sample_rate(48000);
+	for mode in 1..=3 {
+		let (_, min, max) = JUNO[mode - 1];
+		chorus.reset();
+		chorus.set(chorus.find("mode").unwrap(), mode as f64);
+		let delays = ramp(&mut chorus, 48000);
+		// either side of the same centre, so the two always add up alike
+		let sum = delays[4800][0] + delays[4800][1];
+		let mut width: Sample = 0.;
+		for d in delays[4800..].iter() {
+			assert!((d[0] + d[1] - sum).abs() < 1e-6);
+			width = width.max((d[0] - d[1]).abs());
+		}
+		assert!((width - (max - min) * 48000.).abs() < 1.5, "mode {}: {}", mode, width);
+	}
+}
@@ -0,0 +1,550 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::f64::consts::PI;
+use rustfft::{Fft, FftPlanner};
+use rustfft::num_complex::Complex;
+
+use super::types::{SampleRated, SampleRate, Sample, Seconds};
+use super::effects::{Effect, Param, clamp};
+use super::delay::Line;
+
+/// Samples per partition at the head of the response, which is also the
+/// latency of the reverb.
+pub const BLOCK: usize = 128;
+/// The partition sizes, each taking over from the last further into the
+/// response.
+const STAGES: [usize; 3] = [BLOCK, 8 * BLOCK, 64 * BLOCK];
+
+const MAX_PRE_DELAY: Seconds = 0.5;
+/// Faded out at the end of a trimmed response, so it doesn't stop dead.
+const FADE_OUT: Seconds = 0.01;
+
+const MIN_LENGTH: Seconds = 0.05;
+const MAX_LENGTH: Seconds = 20.;
+const MIN_STRETCH: f64 = 0.5;
+const MAX_STRETCH: f64 = 2.;
+
+const PARAMS: [Param; 1] = [
+	Param { name: "pre_delay", min: 0., max: MAX_PRE_DELAY * 1000., default: 0. },
+];
+
+/// An impulse response, one channel for mono or two for stereo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Impulse {
+	pub sample_rate: SampleRate,
+	pub channels: Vec<Vec<Sample>>,
+}
+
+impl Impulse {
+	pub fn new(sample_rate: SampleRate, channels: Vec<Vec<Sample>>) -> Impulse {
+		Impulse { sample_rate, channels }
+	}
+	/// Reads a WAV file, integer or float. Beyond the first two channels are
+	/// ignored.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Impulse, anyhow::Error> {
+		let mut reader = hound::WavReader::open(path)?;
+		let spec = reader.spec();
+		let samples: Vec<Sample> = match spec.sample_format {
+			hound::SampleFormat::Float => reader.samples::<f32>()
+				.map(|s| s.map(|s| s as Sample))
+				.collect::<Result<_, _>>()?,
+			hound::SampleFormat::Int => {
+				let scale = (1u64 << (spec.bits_per_sample - 1)) as Sample;
+				reader.samples::<i32>()
+					.map(|s| s.map(|s| s as Sample / scale))
+					.collect::<Result<_, _>>()?
+			},
+		};
+		let stride = spec.channels as usize;
+		if stride == 0 || samples.is_empty() {
+			anyhow::bail!("impulse response has no samples");
+		}
+		let channels = (0..stride.min(2))
+			.map(|c| samples.iter().skip(c).step_by(stride).cloned().collect())
+			.collect();
+		Ok(Impulse::new(spec.sample_rate, channels))
+	}
+}
+
+/// A forward and inverse FFT of real samples, through a complex FFT of
+/// half the length. Spectra keep only the bins up to Nyquist, the rest
+/// being their mirror image.
+struct RealFft {
+	forward: Arc<dyn Fft<Sample>>,
+	inverse: Arc<dyn Fft<Sample>>,
+	/// e^(-2πik/len), for each of the bins kept.
+	twiddles: Vec<Complex<Sample>>,
+	buffer: Vec<Complex<Sample>>,
+	scratch: Vec<Complex<Sample>>,
+}
+
+impl RealFft {
+	fn new(len: usize, planner: &mut FftPlanner<Sample>) -> RealFft {
+		let half = len / 2;
+		let forward = planner.plan_fft_forward(half);
+		let inverse = planner.plan_fft_inverse(half);
+		let scratch = forward.get_inplace_scratch_len().max(inverse.get_inplace_scratch_len());
+		RealFft {
+			forward,
+			inverse,
+			twiddles: (0..=half).map(|k| Complex::from_polar(1., -2. * PI * k as Sample / len as Sample)).collect(),
+			buffer: vec![Complex::new(0., 0.); half],
+			scratch: vec![Complex::new(0., 0.); scratch],
+		}
+	}
+	/// `len` samples in, `len / 2 + 1` bins out.
+	fn forward(&mut self, input: &[Sample], spectrum: &mut [Complex<Sample>]) {
+		let half = self.buffer.len();
+		// even samples in the real part, odd in the imaginary
+		for (z, pair) in self.buffer.iter_mut().zip(input.chunks(2)) {
+			*z = Complex::new(pair[0], pair[1]);
+		}
+		self.forward.process_with_scratch(&mut self.buffer, &mut self.scratch);
+		for (k, x) in spectrum.iter_mut().enumerate() {
+			let a = self.buffer[k % half];
+			let b = self.buffer[(half - k) % half].conj();
+			let even = (a + b) * 0.5;
+			let odd = (a - b) * Complex::new(0., -0.5);
+			*x = even + self.twiddles[k] * odd;
+		}
+	}
+	/// The inverse of `forward`, scaled to give back the same samples.
+	fn inverse(&mut self, spectrum: &[Complex<Sample>], output: &mut [Sample]) {
+		let half = self.buffer.len();
+		for (k, z) in self.buffer.iter_mut().enumerate() {
+			let a = spectrum[k];
+			let b = spectrum[half - k].conj();
+			let even = (a + b) * 0.5;
+			let odd = (a - b) * 0.5 * self.twiddles[k].conj();
+			*z = even + Complex::new(-odd.im, odd.re);
+		}
+		self.inverse.process_with_scratch(&mut self.buffer, &mut self.scratch);
+		let scale = 1. / half as Sample;
+		for (pair, z) in output.chunks_mut(2).zip(self.buffer.iter()) {
+			pair[0] = z.re * scale;
+			pair[1] = z.im * scale;
+		}
+	}
+}
+
+/// Uniformly partitioned overlap-save convolution with one section of a
+/// response. Every `block` samples the last two blocks of input are
+/// transformed, kept in a ring of spectra, and multiplied with the
+/// matching partition of the section. Only the newest spectrum has to wait
+/// for the block to end; the older ones are added up a few partitions at a
+/// time as the block's samples come in, to spread the work out.
+struct Stage {
+	block: usize,
+	fft: RealFft,
+	partitions: Vec<Vec<Complex<Sample>>>,
+	spectra: Vec<Vec<Complex<Sample>>>,
+	newest: usize,
+	/// Partitions already added into `sum` for the next block.
+	done: usize,
+	input: Vec<Sample>,
+	output: Vec<Sample>,
+	sum: Vec<Complex<Sample>>,
+	time: Vec<Sample>,
+	pos: usize,
+}
+
+impl Stage {
+	fn new(section: &[Sample], block: usize, planner: &mut FftPlanner<Sample>) -> Stage {
+		let mut fft = RealFft::new(2 * block, planner);
+		let mut padded = vec![0.; 2 * block];
+		let partitions: Vec<_> = section.chunks(block).map(|chunk| {
+			padded[..chunk.len()].copy_from_slice(chunk);
+			for h in padded[chunk.len()..].iter_mut() {
+				*h = 0.;
+			}
+			let mut partition = vec![Complex::new(0., 0.); block + 1];
+			fft.forward(&padded, &mut partition);
+			partition
+		}).collect();
+		Stage {
+			block,
+			fft,
+			spectra: vec![vec![Complex::new(0., 0.); block + 1]; partitions.len()],
+			partitions,
+			newest: 0,
+			done: 1,
+			input: vec![0.; 2 * block],
+			output: vec![0.; block],
+			sum: vec![Complex::new(0., 0.); block + 1],
+			time: vec![0.; 2 * block],
+			pos: 0,
+		}
+	}
+	fn reset(&mut self) {
+		for s in self.spectra.iter_mut().flat_map(|spectrum| spectrum.iter_mut()).chain(self.sum.iter_mut()) {
+			*s = Complex::new(0., 0.);
+		}
+		for x in self.input.iter_mut().chain(self.output.iter_mut()) {
+			*x = 0.;
+		}
+		self.done = 1;
+		self.pos = 0;
+	}
+	/// Adds partitions up to `to` into the sum, each with the spectrum as
+	/// many blocks older than the one still to come.
+	fn accumulate(&mut self, to: usize) {
+		let count = self.partitions.len();
+		for k in self.done..to {
+			let spectrum = &self.spectra[(self.newest + count + 1 - k) % count];
+			for ((s, x), h) in self.sum.iter_mut().zip(spectrum.iter()).zip(self.partitions[k].iter()) {
+				*s += x * h;
+			}
+		}
+		self.done = self.done.max(to);
+	}
+	fn process(&mut self, x: Sample) -> Sample {
+		let y = self.output[self.pos];
+		self.input[self.block + self.pos] = x;
+		self.pos += 1;
+		let count = self.partitions.len();
+		self.accumulate(1 + (count - 1) * self.pos / self.block);
+		if self.pos == self.block {
+			self.pos = 0;
+			self.block();
+		}
+		y
+	}
+	fn block(&mut self) {
+		let count = self.partitions.len();
+		self.newest = (self.newest + 1) % count;
+		self.fft.forward(&self.input, &mut self.spectra[self.newest]);
+		self.input.copy_within(self.block.., 0);
+		for ((s, x), h) in self.sum.iter_mut().zip(self.spectra[self.newest].iter()).zip(self.partitions[0].iter()) {
+			*s += x * h;
+		}
+		self.fft.inverse(&self.sum, &mut self.time);
+		// the first half wraps around, only the second is the convolution
+		self.output.copy_from_slice(&self.time[self.block..]);
+		for s in self.sum.iter_mut() {
+			*s = Complex::new(0., 0.);
+		}
+		self.done = 1;
+	}
+}
+
+/// Non-uniformly partitioned convolution of one channel: the head of the
+/// response in short blocks to keep the latency down to `BLOCK`, and the
+/// tail in longer ones, which cost far less per sample. Each stage's
+/// section starts as far in as its extra latency, so they line up without
+/// any delay between them.
+struct Convolver {
+	stages: Vec<Stage>,
+}
+
+impl Convolver {
+	fn new(response: &[Sample], planner: &mut FftPlanner<Sample>) -> Convolver {
+		let mut stages = vec![];
+		for (i, &block) in STAGES.iter().enumerate() {
+			let from = block - BLOCK;
+			if from >= response.len() && i > 0 {
+				break;
+			}
+			let to = match STAGES.get(i + 1) {
+				Some(next) => (next - BLOCK).min(response.len()),
+				None => response.len(),
+			};
+			stages.push(Stage::new(&response[from.min(to)..to], block, planner));
+		}
+		Convolver { stages }
+	}
+	fn reset(&mut self) {
+		for stage in self.stages.iter_mut() {
+			stage.reset();
+		}
+	}
+	fn process(&mut self, x: Sample) -> Sample {
+		self.stages.iter_mut().map(|stage| stage.process(x)).sum()
+	}
+}
+
+/// How much of an `Impulse` to use: `start` cuts the head and `length` the
+/// tail, and `stretch` plays it slower or faster to make the space larger
+/// or smaller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trim {
+	pub start: Seconds,
+	pub length: Seconds,
+	pub stretch: f64,
+}
+
+impl Trim {
+	pub fn new() -> Trim {
+		Trim { start: 0., length: MAX_LENGTH, stretch: 1. }
+	}
+}
+
+/// Reverb from a recorded space, convolving each side of the mix with an
+/// `Impulse`; a mono response is used for both. The response is trimmed
+/// as it is loaded and normalised to unity energy, and like every effect
+/// the wet/dry balance is set by the `Chain`.
+///
+/// Loading a response resamples and partitions all of it, allocating and
+/// running FFTs on the calling thread. Rather than load one into a
+/// `Convolution` in a running `Chain`, set up a new one elsewhere, with
+/// the sample rate first, and swap it in with `Effects::remove` and
+/// `Effects::insert`; at an unchanged sample rate nothing is prepared
+/// again.
+pub struct Convolution {
+	impulse: Option<Impulse>,
+	trim: Trim,
+	pre_delay: Seconds,
+	pre_delays: Vec<Line>,
+	convolvers: Vec<Convolver>,
+	sample_rate: f64,
+}
+
+impl Convolution {
+	pub fn new() -> Convolution {
+		Convolution {
+			impulse: None,
+			trim: Trim::new(),
+			pre_delay: PARAMS[0].default / 1000.,
+			pre_delays: (0..2).map(|_| Line::new()).collect(),
+			convolvers: Vec::new(),
+			sample_rate: 0.,
+		}
+	}
+	pub fn set_impulse(&mut self, impulse: Impulse, trim: Trim) {
+		self.impulse = Some(impulse);
+		self.trim = Trim {
+			start: trim.start.max(0.),
+			length: trim.length.max(MIN_LENGTH).min(MAX_LENGTH),
+			stretch: trim.stretch.max(MIN_STRETCH).min(MAX_STRETCH),
+		};
+		self.prepare();
+	}
+	pub fn impulse(&self) -> Option<&Impulse> {
+		self.impulse.as_ref()
+	}
+	pub fn trim(&self) -> Trim {
+		self.trim
+	}
+	/// Trims, stretches and resamples each channel of the response to the
+	/// running sample rate.
+	fn responses(&self) -> Vec<Vec<Sample>> {
+		let impulse = match &self.impulse {
+			Some(impulse) if self.sample_rate > 0. => impulse,
+			_ => return Vec::new(),
+		};
+		let rate = self.sample_rate * self.trim.stretch;
+		let mut responses: Vec<Vec<Sample>> = impulse.channels.iter().map(|channel| {
+			let from = self.trim.start * impulse.sample_rate as f64;
+			let duration = (channel.len() as f64 - from).max(0.) / impulse.sample_rate as f64;
+			let len = (duration.min(self.trim.length) * rate) as usize;
+			(0..len).map(|i| {
+				let pos = from + i as f64 * impulse.sample_rate as f64 / rate;
+				let j = pos.floor() as usize;
+				let frac = pos - pos.floor();
+				let a = channel[j];
+				let b = channel.get(j + 1).cloned().unwrap_or(0.);
+				a + (b - a) * frac
+			}).collect()
+		}).collect();
+
+		let fade = (FADE_OUT * self.sample_rate) as usize;
+		let mut energy = 0.;
+		for response in responses.iter_mut() {
+			let len = response.len();
+			for (i, h) in response.iter_mut().enumerate().skip(len.saturating_sub(fade)) {
+				*h *= (len - i) as Sample / fade as Sample;
+			}
+			energy += response.iter().map(|h| h * h).sum::<Sample>();
+		}
+		let scale = (energy / responses.len() as Sample).sqrt();
+		if scale > 0. {
+			for h in responses.iter_mut().flat_map(|response| response.iter_mut()) {
+				*h /= scale;
+			}
+		}
+		responses
+	}
+	fn prepare(&mut self) {
+		let mut planner = FftPlanner::new();
+		let silence = [0.];
+		let responses = self.responses();
+		// trimmed away entirely, a channel still needs a partition to run
+		let responses: Vec<&[Sample]> = responses.iter()
+			.map(|response| if response.is_empty() { &silence[..] } else { &response[..] })
+			.collect();
+		self.convolvers = responses.iter()
+			.chain(responses.iter())
+			.take(2)
+			.map(|response| Convolver::new(response, &mut planner))
+			.collect();
+	}
+}
+
+impl SampleRated for Convolution {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		if sample_rate as f64 == self.sample_rate {
+			return;
+		}
+		self.sample_rate = sample_rate as f64;
+		let len = (MAX_PRE_DELAY * self.sample_rate).ceil() as usize + 2;
+		for line in self.pre_delays.iter_mut() {
+			line.resize(len);
+		}
+		self.prepare();
+	}
+}
+
+impl Effect for Convolution {
+	fn name(&self) -> &'static str {
+		"Convolution"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		if self.convolvers.is_empty() {
+			return [0.; 2];
+		}
+		let pre_delay = (self.pre_delay * self.sample_rate).max(1.);
+		let mut out = [0.; 2];
+		for c in 0..2 {
+			let x = self.pre_delays[c].read(pre_delay);
+			self.pre_delays[c].write(input[c]);
+			out[c] = self.convolvers[c].process(x);
+		}
+		out
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0 => self.pre_delay * 1000.,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		self.pre_delay = clamp(&PARAMS, param, value) / 1000.;
+	}
+	fn reset(&mut self) {
+		for line in self.pre_delays.iter_mut() {
+			line.clear();
+		}
+		for convolver in self.convolvers.iter_mut() {
+			convolver.reset();
+		}
+	}
+}
+
+#[cfg(test)]
+fn noise(len: usize) -> Vec<Sample> {
+	use rand::Rng;
+	let mut rng = rand::thread_rng();
+	(0..len).map(|_| rng.gen::<Sample>() - 0.5).collect()
+}
+
+#[test]
+fn test_convolution() {
+	// a response spanning several partitions, against direct convolution
+	let response = noise(5 * BLOCK + 17);
+	let mut convolution = Convolution::new();
+	convolution.set_sample_rate(48000);
+	convolution.set_impulse(Impulse::new(48000, vec![response.clone()]), Trim { length: 1., ..Trim::new() });
+	let prepared = convolution.responses();
+	assert_eq!(prepared.len(), 1);
+	let norm: Sample = prepared[0].iter().map(|h| h * h).sum();
+	assert!((norm - 1.).abs() < 1e-9);
+
+	let input = noise(4000);
+	let out: Vec<_> = input.iter().map(|x| convolution.process([*x, -x])).collect();
+	// the pre-delay line adds a sample to the block of latency
+	let latency = BLOCK + 1;
+	for n in latency..out.len() {
+		let i = n - latency;
+		let expected: Sample = (0..=i.min(prepared[0].len() - 1))
+			.map(|k| prepared[0][k] * input[i - k])
+			.sum();
+		assert!((out[n][0] - expected).abs() < 1e-9, "{}: {} {}", n, out[n][0], expected);
+		assert!((out[n][1] + expected).abs() < 1e-9);
+	}
+	assert!(out[..latency].iter().all(|s| s[0].abs() < 1e-9 && s[1].abs() < 1e-9));
+}
+
+#[test]
+fn test_convolution_stages() {
+	// long enough to reach the third stage, and still exact
+	let response = noise(STAGES[2] + 3 * BLOCK);
+	let mut convolution = Convolution::new();
+	convolution.set_sample_rate(48000);
+	convolution.set_impulse(Impulse::new(48000, vec![response]), Trim::new());
+	assert_eq!(convolution.convolvers[0].stages.len(), 3);
+	let prepared = convolution.responses();
+
+	let input = noise(3 * STAGES[2]);
+	let out: Vec<_> = input.iter().map(|x| convolution.process([*x, *x])[0]).collect();
+	let latency = BLOCK + 1;
+	for n in (latency..out.len()).step_by(5) {
+		let i = n - latency;
+		let expected: Sample = (0..=i.min(prepared[0].len() - 1))
+			.map(|k| prepared[0][k] * input[i - k])
+			.sum();
+		assert!((out[n] - expected).abs() < 1e-9, "{}: {} {}", n, out[n], expected);
+	}
+}
+
+#[test]
+fn test_convolution_trim() {
+	// stereo, a click 10 ms in on the left and 20 ms on the right
+	let mut left = vec![0.; 4800];
+	let mut right = vec![0.; 4800];
+	left[480] = 1.;
+	right[960] = 1.;
+	let impulse = Impulse::new(48000, vec![left, right]);
+	let mut convolution = Convolution::new();
+	convolution.set_impulse(impulse.clone(), Trim::new());
+	convolution.set_sample_rate(48000);
+	let click = |convolution: &mut Convolution| -> [usize; 2] {
+		convolution.reset();
+		let out: Vec<_> = (0..9600).map(|i| convolution.process(if i == 0 { [1.; 2] } else { [0.; 2] })).collect();
+		let mut at = [0; 2];
+		for c in 0..2 {
+			at[c] = (0..out.len()).max_by(|&a, &b| out[a][c].abs().partial_cmp(&out[b][c].abs()).unwrap()).unwrap();
+		}
+		at
+	};
+	assert_eq!(click(&mut convolution), [BLOCK + 1 + 480, BLOCK + 1 + 960]);
+	let trim = Trim { start: 0.005, ..Trim::new() };
+	convolution.set_impulse(impulse.clone(), trim);
+	assert_eq!(click(&mut convolution), [BLOCK + 1 + 240, BLOCK + 1 + 720]);
+	convolution.set_impulse(impulse.clone(), Trim { stretch: 2., ..trim });
+	assert_eq!(click(&mut convolution), [BLOCK + 1 + 480, BLOCK + 1 + 1440]);
+	convolution.set(convolution.find("pre_delay").unwrap(), 10.);
+	assert_eq!(click(&mut convolution), [BLOCK + 480 + 480, BLOCK + 480 + 1440]);
+
+	// cut short before the right click, which then never arrives
+	convolution.set_impulse(impulse, Trim { length: 0.05, ..Trim::new() });
+	assert_eq!(convolution.responses()[1].len(), 2400);
+	assert_eq!(convolution.params().len(), 1);
+}
+
+#[test]
+fn test_impulse_load() {
+	let path = std::env::temp_dir().join("feo-test-impulse.wav");
+	let spec = hound::WavSpec {
+		channels: 2,
+		sample_rate: 44100,
+		bits_per_sample: 16,
+		sample_format: hound::SampleFormat::Int,
+	};
+	let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+	for &(l, r) in &[(16384, -16384), (0, 32767), (-32768, 0)] {
+		writer.write_sample(l as i16).unwrap();
+		writer.write_sample(r as i16).unwrap();
+	}
+	writer.finalize().unwrap();
+
+	let impulse = Impulse::load(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(impulse.sample_rate, 44100);
+	assert_eq!(impulse.channels, vec![vec![0.5, 0., -1.], vec![-0.5, 32767. / 32768., 0.]]);
+	assert!(Impulse::load(std::env::temp_dir().join("feo-missing.wav")).is_err());
+}
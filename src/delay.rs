@@ -0,0 +1,367 @@
+use super::types::{SampleRated, SampleRate, Sample, Seconds};
+use super::effects::{Effect, Param, clamp};
+use super::filter::{Svf, Mode};
+use super::smoother::Smoother;
+use super::tempo::DIVISIONS;
+
+/// Longest delay on either side; tempo synced times are held to it.
+pub const MAX_TIME: Seconds = 4.;
+
+/// How long a change of time takes to glide to its new length.
+const GLIDE_TIME: Seconds = 0.1;
+
+const PARAMS: [Param; 11] = [
+	Param { name: "left_time", min: 1., max: MAX_TIME * 1000., default: 375. },
+	Param { name: "right_time", min: 1., max: MAX_TIME * 1000., default: 500. },
+	Param { name: "sync", min: 0., max: 1., default: 0. },
+	Param { name: "left_division", min: 0., max: 11., default: 7. },
+	Param { name: "right_division", min: 0., max: 11., default: 2. },
+	Param { name: "feedback", min: 0., max: 1., default: 0.4 },
+	Param { name: "cross_feedback", min: 0., max: 1., default: 0. },
+	Param { name: "low_cut", min: 20., max: 2000., default: 20. },
+	Param { name: "high_cut", min: 500., max: 20000., default: 12000. },
+	Param { name: "drive", min: 0., max: 1., default: 0. },
+	Param { name: "freeze", min: 0., max: 1., default: 0. },
+];
+
+/// A circular buffer for delay based effects. It is empty until `resize`,
+/// which the effects call from `set_sample_rate` so nothing allocates while
+/// running.
+pub struct Line {
+	buffer: Vec<Sample>,
+	pos: usize,
+}
+
+impl Line {
+	pub fn new() -> Line {
+		Line { buffer: Vec::new(), pos: 0 }
+	}
+	/// Makes room for delays of up to `len - 1` samples, clearing the line.
+	pub fn resize(&mut self, len: usize) {
+		self.buffer = vec![0.; len];
+		self.pos = 0;
+	}
+	pub fn len(&self) -> usize {
+		self.buffer.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.buffer.is_empty()
+	}
+	pub fn clear(&mut self) {
+		for x in self.buffer.iter_mut() {
+			*x = 0.;
+		}
+	}
+	/// Reads `delay` samples back, between samples for fractional delays.
+	pub fn read(&self, delay: f64) -> Sample {
+		let len = self.buffer.len();
+		let pos = (self.pos + len) as f64 - delay;
+		let i = pos.floor();
+		let frac = pos - i;
+		let a = self.buffer[i as usize % len];
+		let b = self.buffer[(i as usize + 1) % len];
+		a + (b - a) * frac
+	}
+	pub fn write(&mut self, x: Sample) {
+		self.buffer[self.pos] = x;
+		self.pos = (self.pos + 1) % self.buffer.len();
+	}
+}
+
+/// Stereo delay. Times are in milliseconds, or divisions of the beat from
+/// `DIVISIONS` when synced, and glide to a new setting rather than jump.
+/// Cross feedback sends the repeats to the other side, bouncing between
+/// the two at 1. The feedback runs through a band of `low_cut` to
+/// `high_cut` and a soft clipper, pushed harder by `drive`; freezing
+/// stops the input and holds the repeats as they are.
+pub struct Delay {
+	left_time: f64,
+	right_time: f64,
+	sync: bool,
+	left_division: usize,
+	right_division: usize,
+	feedback: Sample,
+	cross_feedback: Sample,
+	low_cut: f64,
+	high_cut: f64,
+	drive: f64,
+	freeze: bool,
+	bpm: f64,
+	lines: [Line; 2],
+	times: [Smoother; 2],
+	highpass: [Svf; 2],
+	lowpass: [Svf; 2],
+	sample_rate: f64,
+}
+
+impl Delay {
+	pub fn new() -> Delay {
+		let mut delay = Delay {
+			left_time: PARAMS[0].default,
+			right_time: PARAMS[1].default,
+			sync: false,
+			left_division: PARAMS[3].default as usize,
+			right_division: PARAMS[4].default as usize,
+			feedback: PARAMS[5].default,
+			cross_feedback: PARAMS[6].default,
+			low_cut: PARAMS[7].default,
+			high_cut: PARAMS[8].default,
+			drive: PARAMS[9].default,
+			freeze: false,
+			bpm: 120.,
+			lines: [Line::new(), Line::new()],
+			times: [Smoother::new(GLIDE_TIME); 2],
+			highpass: [Svf::new(); 2],
+			lowpass: [Svf::new(); 2],
+			sample_rate: 0.,
+		};
+		for highpass in delay.highpass.iter_mut() {
+			highpass.set_mode(Mode::Highpass);
+		}
+		delay
+	}
+	/// The delay on each side, in seconds, as currently set.
+	pub fn times(&self) -> [Seconds; 2] {
+		if self.sync {
+			[
+				DIVISIONS[self.left_division].seconds(self.bpm).min(MAX_TIME),
+				DIVISIONS[self.right_division].seconds(self.bpm).min(MAX_TIME),
+			]
+		} else {
+			[self.left_time / 1000., self.right_time / 1000.]
+		}
+	}
+	fn update_times(&mut self) {
+		let max = (MAX_TIME * self.sample_rate).ceil();
+		let times = self.times();
+		for (smoother, time) in self.times.iter_mut().zip(times.iter()) {
+			smoother.set((time * self.sample_rate).max(1.).min(max));
+		}
+	}
+	fn update_filters(&mut self) {
+		for (highpass, lowpass) in self.highpass.iter_mut().zip(self.lowpass.iter_mut()) {
+			highpass.set(self.low_cut, 0.);
+			lowpass.set(self.high_cut, 0.);
+		}
+	}
+	fn saturate(&self, x: Sample) -> Sample {
+		if self.drive == 0. {
+			return x;
+		}
+		let gain = 1. + 4. * self.drive;
+		(x * gain).tanh() / gain
+	}
+}
+
+impl SampleRated for Delay {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as f64;
+		let len = (MAX_TIME * self.sample_rate).ceil() as usize + 2;
+		for line in self.lines.iter_mut() {
+			line.resize(len);
+		}
+		for filter in self.highpass.iter_mut().chain(self.lowpass.iter_mut()) {
+			filter.set_sample_rate(sample_rate);
+		}
+		for smoother in self.times.iter_mut() {
+			smoother.set_sample_rate(sample_rate);
+		}
+		self.update_times();
+		for smoother in self.times.iter_mut() {
+			smoother.reset(smoother.target());
+		}
+		self.update_filters();
+	}
+}
+
+impl Effect for Delay {
+	fn name(&self) -> &'static str {
+		"Delay"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		if self.lines[0].is_empty() {
+			return [0.; 2];
+		}
+		let mut out = [0.; 2];
+		for c in 0..2 {
+			let time = self.times[c].run();
+			// whole samples while frozen, so the loop doesn't blur on every pass
+			let time = if self.freeze { time.round() } else { time };
+			out[c] = self.lines[c].read(time);
+		}
+		for c in 0..2 {
+			let x = if self.freeze {
+				out[c]
+			} else {
+				let fb = out[c] * (1. - self.cross_feedback) + out[1 - c] * self.cross_feedback;
+				let fb = self.lowpass[c].process(self.highpass[c].process(fb));
+				input[c] + self.saturate(fb * self.feedback)
+			};
+			self.lines[c].write(x);
+		}
+		out
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0  => self.left_time,
+			1  => self.right_time,
+			2  => if self.sync { 1. } else { 0. },
+			3  => self.left_division as f64,
+			4  => self.right_division as f64,
+			5  => self.feedback,
+			6  => self.cross_feedback,
+			7  => self.low_cut,
+			8  => self.high_cut,
+			9  => self.drive,
+			10 => if self.freeze { 1. } else { 0. },
+			_  => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		let value = clamp(&PARAMS, param, value);
+		match param {
+			0  => self.left_time = value,
+			1  => self.right_time = value,
+			2  => self.sync = value >= 0.5,
+			3  => self.left_division = value.round() as usize,
+			4  => self.right_division = value.round() as usize,
+			5  => self.feedback = value,
+			6  => self.cross_feedback = value,
+			7  => self.low_cut = value,
+			8  => self.high_cut = value,
+			9  => self.drive = value,
+			_  => self.freeze = value >= 0.5,
+		}
+		self.update_times();
+		self.update_filters();
+	}
+	fn reset(&mut self) {
+		for line in self.lines.iter_mut() {
+			line.clear();
+		}
+		for filter in self.highpass.iter_mut().chain(self.lowpass.iter_mut()) {
+			filter.reset();
+		}
+	}
+	fn set_bpm(&mut self, bpm: f64) {
+		self.bpm = bpm;
+		self.update_times();
+	}
+}
+
+#[cfg(test)]
+fn impulse(delay: &mut Delay, samples: usize) -> Vec<[Sample; 2]> {
+	(0..samples).map(|i| delay.process(if i == 0 { [1., 0.] } else { [0.; 2] })).collect()
+}
+
+#[test]
+fn test_delay_times() {
+	let mut delay = Delay::new();
+	delay.set(delay.find("left_time").unwrap(), 100.);
+	delay.set(delay.find("right_time").unwrap(), 250.);
+	delay.set(delay.find("feedback").unwrap(), 0.);
+	delay.set_sample_rate(1000);
+	let out = impulse(&mut delay, 300);
+	assert_eq!(out[100][0], 1.);
+	assert!(out.iter().enumerate().all(|(i, s)| i == 100 || s[0] == 0.));
+	assert!(out.iter().all(|s| s[1] == 0.));
+
+	// a dotted eighth and a quarter note
+	delay.set(2, 1.);
+	delay.set_sample_rate(1000);
+	assert_eq!(delay.times(), [0.375, 0.5]);
+	delay.set_bpm(60.);
+	assert_eq!(delay.times(), [0.75, 1.]);
+	delay.set_bpm(10.);
+	assert_eq!(delay.times(), [MAX_TIME, MAX_TIME]);
+
+	// a new time glides there rather than jumping
+	delay.set(2, 0.);
+	delay.set_sample_rate(1000);
+	let before = delay.times[0].value();
+	delay.set(0, 200.);
+	delay.process([0.; 2]);
+	assert!(delay.times[0].value() > before && delay.times[0].value() < 101.);
+	for _ in 0..5000 {
+		delay.process([0.; 2]);
+	}
+	assert_eq!(delay.times[0].value(), 200.);
+}
+
+/// Ten milliseconds of a 1 kHz sine on the left, then silence, at 48 kHz.
+#[cfg(test)]
+fn burst(delay: &mut Delay, samples: usize) -> Vec<[Sample; 2]> {
+	use std::f64::consts::PI;
+	(0..samples).map(|i| {
+		let x = if i < 480 { (2. * PI * i as f64 / 48.).sin() } else { 0. };
+		delay.process([x, 0.])
+	}).collect()
+}
+
+#[cfg(test)]
+fn peak(out: &[[Sample; 2]], repeat: usize, c: usize) -> Sample {
+	out[repeat * 480..(repeat + 1) * 480].iter().fold(0., |m: Sample, s| m.max(s[c].abs()))
+}
+
+#[test]
+fn test_delay_feedback() {
+	let mut delay = Delay::new();
+	delay.set(0, 10.);
+	delay.set(1, 10.);
+	delay.set(delay.find("feedback").unwrap(), 0.5);
+	delay.set(delay.find("high_cut").unwrap(), 20000.);
+	delay.set_sample_rate(48000);
+	let out = burst(&mut delay, 2400);
+	assert!((peak(&out, 1, 0) - 1.).abs() < 1e-6);
+	assert!((peak(&out, 2, 0) - 0.5).abs() < 0.03);
+	assert!((peak(&out, 3, 0) - 0.25).abs() < 0.03);
+	assert!(out.iter().all(|s| s[1] == 0.));
+
+	// ping-pong: every repeat crosses to the other side
+	delay.reset();
+	delay.set(delay.find("cross_feedback").unwrap(), 1.);
+	let out = burst(&mut delay, 2400);
+	assert!(peak(&out, 2, 0) < 0.05 && (peak(&out, 2, 1) - 0.5).abs() < 0.03);
+	assert!(peak(&out, 3, 1) < 0.05 && (peak(&out, 3, 0) - 0.25).abs() < 0.03);
+
+	// the feedback filters take the lows out of the repeats
+	delay.reset();
+	delay.set(6, 0.);
+	delay.set(delay.find("low_cut").unwrap(), 2000.);
+	let out = burst(&mut delay, 2400);
+	assert!(peak(&out, 2, 0) < 0.25);
+
+	// flat out, with drive, the repeats stay bounded
+	delay.reset();
+	delay.set(5, 1.);
+	delay.set(9, 1.);
+	for i in 0..48000 {
+		let [l, r] = delay.process([if i % 100 == 0 { 1. } else { 0. }; 2]);
+		assert!(l.abs() <= 1.5 && r.abs() <= 1.5);
+	}
+}
+
+#[test]
+fn test_delay_freeze() {
+	let mut delay = Delay::new();
+	delay.set(0, 100.);
+	delay.set(delay.find("feedback").unwrap(), 0.);
+	delay.set_sample_rate(1000);
+	impulse(&mut delay, 50);
+	delay.set(delay.find("freeze").unwrap(), 1.);
+	// held on every pass, and no new input gets in
+	for pass in 0..10 {
+		for i in 0..100 {
+			let [l, _] = delay.process([1.; 2]);
+			assert_eq!(l, if i == 50 { 1. } else { 0. }, "pass {} sample {}", pass, i);
+		}
+	}
+	delay.set(10, 0.);
+	assert_eq!(delay.get(10), 0.);
+}
@@ -0,0 +1,389 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::{SampleRated, SampleRate, Sample, Frequency};
+use super::effects::{Effect, Param, clamp};
+use super::filter::Svf;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+	Off,
+	SoftClip,
+	HardClip,
+	/// Soft clipping that bends one way harder than the other, adding even
+	/// harmonics.
+	Tube,
+	/// Folds back whatever goes past full scale.
+	Fold,
+	BitCrush,
+	/// Holds the signal, sampling it at `rate`.
+	Downsample,
+}
+
+/// In the order of the waveshaper effect's "shape" parameter.
+pub const SHAPES: [Shape; 7] = [
+	Shape::Off,
+	Shape::SoftClip,
+	Shape::HardClip,
+	Shape::Tube,
+	Shape::Fold,
+	Shape::BitCrush,
+	Shape::Downsample,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Oversampling {
+	X1,
+	X2,
+	X4,
+	X8,
+}
+
+impl Oversampling {
+	pub fn factor(&self) -> usize {
+		match self {
+			Oversampling::X1 => 1,
+			Oversampling::X2 => 2,
+			Oversampling::X4 => 4,
+			Oversampling::X8 => 8,
+		}
+	}
+}
+
+pub const OVERSAMPLING: [Oversampling; 4] = [
+	Oversampling::X1,
+	Oversampling::X2,
+	Oversampling::X4,
+	Oversampling::X8,
+];
+
+/// Offset giving the tube curve its lean.
+const TUBE_BIAS: Sample = 0.3;
+/// Tone runs the lowpass from here up by `TONE_RANGE` times.
+const TONE_MIN: Frequency = 200.;
+const TONE_RANGE: f64 = 100.;
+/// Two pole filters in the anti-aliasing lowpass.
+const ANTIALIAS: usize = 4;
+/// Butterworth damping for the anti-aliasing filters.
+const BUTTERWORTH: Sample = 1. - std::f64::consts::SQRT_2 / 2.;
+
+/// Waveshaper settings, part of the `Patch` for the shaper each voice has
+/// ahead of its filter. The curves are oversampled to keep their harmonics
+/// from folding back below the Nyquist frequency; crushing and
+/// downsampling run at the plain rate, their aliasing being the point.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Distortion {
+	pub shape: Shape,
+	/// From 0 to 1, up to 40 dB of gain into the curve.
+	pub drive: Sample,
+	/// Lowpass after the curve, from 0 (dark) to 1 (open).
+	pub tone: Sample,
+	pub bits: u8,
+	pub rate: Frequency,
+	pub oversampling: Oversampling,
+}
+
+impl Distortion {
+	pub fn new() -> Distortion {
+		Distortion {
+			shape: Shape::Off,
+			drive: 0.5,
+			tone: 1.,
+			bits: 8,
+			rate: 8000.,
+			oversampling: Oversampling::X4,
+		}
+	}
+	pub fn gain(&self) -> Sample {
+		(10.0 as f64).powf(2. * self.drive.max(0.).min(1.))
+	}
+	/// The curve alone, without drive, for everything but downsampling.
+	pub fn curve(&self, x: Sample) -> Sample {
+		match self.shape {
+			Shape::Off        => x,
+			Shape::SoftClip   => x.tanh(),
+			Shape::HardClip   => x.max(-1.).min(1.),
+			Shape::Tube       => (x + TUBE_BIAS).tanh() - TUBE_BIAS.tanh(),
+			Shape::Fold       => 1. - ((x + 1.).rem_euclid(4.) - 2.).abs(),
+			Shape::BitCrush   => {
+				let levels = (2.0 as f64).powi(self.bits.max(1).min(16) as i32 - 1);
+				(x.max(-1.).min(1.) * levels).round() / levels
+			},
+			Shape::Downsample => x,
+		}
+	}
+}
+
+/// The running state of a waveshaper, one per voice or per side.
+#[derive(Clone, Copy, Debug)]
+pub struct Shaper {
+	previous: Sample,
+	antialias: [Svf; ANTIALIAS],
+	factor: usize,
+	lowpass: Svf,
+	tone: Sample,
+	held: Sample,
+	hold: f64,
+	sample_rate: SampleRate,
+}
+
+impl SampleRated for Shaper {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate;
+		self.lowpass.set_sample_rate(sample_rate);
+		// set up again on the next sample
+		self.factor = 0;
+		self.tone = -1.;
+	}
+}
+
+impl Shaper {
+	pub fn new() -> Shaper {
+		Shaper {
+			previous: 0.,
+			antialias: [Svf::new(); ANTIALIAS],
+			factor: 0,
+			lowpass: Svf::new(),
+			tone: -1.,
+			held: 0.,
+			hold: 1.,
+			sample_rate: 0,
+		}
+	}
+	pub fn reset(&mut self) {
+		self.previous = 0.;
+		for filter in self.antialias.iter_mut() {
+			filter.reset();
+		}
+		self.lowpass.reset();
+		self.held = 0.;
+		self.hold = 1.;
+	}
+	fn set_factor(&mut self, factor: usize) {
+		self.factor = factor;
+		let nyquist = self.sample_rate as Frequency / 2.;
+		for filter in self.antialias.iter_mut() {
+			filter.set_sample_rate(self.sample_rate * factor as SampleRate);
+			filter.set(0.9 * nyquist, BUTTERWORTH);
+			filter.reset();
+		}
+	}
+	pub fn process(&mut self, distortion: &Distortion, x: Sample) -> Sample {
+		if distortion.shape == Shape::Off || self.sample_rate == 0 {
+			return x;
+		}
+		let gain = distortion.gain();
+		let y = match distortion.shape {
+			Shape::BitCrush => distortion.curve(x * gain),
+			Shape::Downsample => {
+				if self.hold >= 1. {
+					self.hold -= self.hold.floor();
+					self.held = x;
+				}
+				self.hold += distortion.rate / self.sample_rate as Frequency;
+				self.held
+			},
+			_ => {
+				let factor = distortion.oversampling.factor();
+				if factor != self.factor {
+					self.set_factor(factor);
+				}
+				if factor == 1 {
+					distortion.curve(x * gain)
+				} else {
+					// interpolate up, shape, filter, and keep every last sample
+					let mut y = 0.;
+					for i in 1..=factor {
+						let u = self.previous + (x - self.previous) * i as Sample / factor as Sample;
+						y = distortion.curve(u * gain);
+						for filter in self.antialias.iter_mut() {
+							y = filter.process(y);
+						}
+					}
+					self.previous = x;
+					y
+				}
+			},
+		};
+		if distortion.tone >= 1. {
+			return y;
+		}
+		if distortion.tone != self.tone {
+			self.tone = distortion.tone;
+			let cutoff = TONE_MIN * TONE_RANGE.powf(distortion.tone.max(0.));
+			self.lowpass.set(cutoff, BUTTERWORTH);
+		}
+		self.lowpass.process(y)
+	}
+}
+
+const PARAMS: [Param; 6] = [
+	Param { name: "shape", min: 0., max: 6., default: 1. },
+	Param { name: "drive", min: 0., max: 1., default: 0.5 },
+	Param { name: "tone", min: 0., max: 1., default: 1. },
+	Param { name: "bits", min: 1., max: 16., default: 8. },
+	Param { name: "rate", min: 100., max: 48000., default: 8000. },
+	Param { name: "oversampling", min: 0., max: 3., default: 2. },
+];
+
+/// The waveshaper on the master bus; "shape" and "oversampling" choose
+/// from `SHAPES` and `OVERSAMPLING`.
+pub struct Waveshaper {
+	pub distortion: Distortion,
+	shapers: [Shaper; 2],
+}
+
+impl Waveshaper {
+	pub fn new() -> Waveshaper {
+		Waveshaper {
+			distortion: Distortion { shape: Shape::SoftClip, ..Distortion::new() },
+			shapers: [Shaper::new(); 2],
+		}
+	}
+}
+
+impl SampleRated for Waveshaper {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		for shaper in self.shapers.iter_mut() {
+			shaper.set_sample_rate(sample_rate);
+		}
+	}
+}
+
+impl Effect for Waveshaper {
+	fn name(&self) -> &'static str {
+		"Waveshaper"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		[
+			self.shapers[0].process(&self.distortion, input[0]),
+			self.shapers[1].process(&self.distortion, input[1]),
+		]
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		let d = &self.distortion;
+		match param {
+			0 => SHAPES.iter().position(|&shape| shape == d.shape).unwrap_or(0) as f64,
+			1 => d.drive,
+			2 => d.tone,
+			3 => d.bits as f64,
+			4 => d.rate,
+			5 => OVERSAMPLING.iter().position(|&o| o == d.oversampling).unwrap_or(0) as f64,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		let value = clamp(&PARAMS, param, value);
+		let d = &mut self.distortion;
+		match param {
+			0 => d.shape = SHAPES[value.round() as usize],
+			1 => d.drive = value,
+			2 => d.tone = value,
+			3 => d.bits = value.round() as u8,
+			4 => d.rate = value,
+			_ => d.oversampling = OVERSAMPLING[value.round() as usize],
+		}
+	}
+	fn reset(&mut self) {
+		for shaper in self.shapers.iter_mut() {
+			shaper.reset();
+		}
+	}
+}
+
+#[test]
+fn test_curves() {
+	let mut d = Distortion::new();
+	d.shape = Shape::HardClip;
+	assert_eq!(d.curve(0.5), 0.5);
+	assert_eq!(d.curve(-3.), -1.);
+	d.shape = Shape::SoftClip;
+	assert!(d.curve(10.) < 1. && d.curve(10.) > 0.999);
+	d.shape = Shape::Tube;
+	assert_eq!(d.curve(0.), 0.);
+	assert!(d.curve(2.) < -d.curve(-2.));
+	d.shape = Shape::Fold;
+	assert_eq!(d.curve(0.5), 0.5);
+	assert_eq!(d.curve(1.5), 0.5);
+	assert_eq!(d.curve(3.), -1.);
+	assert_eq!(d.curve(-1.25), -0.75);
+	d.shape = Shape::BitCrush;
+	d.bits = 2;
+	assert_eq!(d.curve(0.3), 0.5);
+	assert_eq!(d.curve(0.2), 0.);
+	assert_eq!(d.curve(-0.9), -1.);
+
+	// downsampled to a quarter of the rate, each value is held for four
+	let mut shaper = Shaper::new();
+	shaper.set_sample_rate(48000);
+	let d = Distortion { shape: Shape::Downsample, rate: 12000., ..Distortion::new() };
+	let out: Vec<_> = (0..8).map(|i| shaper.process(&d, i as Sample)).collect();
+	assert_eq!(out, vec![0., 0., 0., 0., 4., 4., 4., 4.]);
+}
+
+/// Level of `freq` in `x`, by Goertzel.
+#[cfg(test)]
+pub(crate) fn level(x: &[Sample], freq: Frequency) -> Sample {
+	use std::f64::consts::PI;
+	let w = 2. * PI * freq / 48000.;
+	let (mut s1, mut s2) = (0., 0.);
+	for v in x {
+		let s = v + 2. * w.cos() * s1 - s2;
+		s2 = s1;
+		s1 = s;
+	}
+	(s1 * s1 + s2 * s2 - 2. * w.cos() * s1 * s2).sqrt() * 2. / x.len() as Sample
+}
+
+#[test]
+fn test_oversampling() {
+	use std::f64::consts::PI;
+	// the harmonics of 5 kHz fold back onto the other multiples of 1 kHz
+	let clipped = |oversampling: Oversampling| {
+		let mut shaper = Shaper::new();
+		shaper.set_sample_rate(48000);
+		let d = Distortion { shape: Shape::HardClip, drive: 0.5, oversampling, ..Distortion::new() };
+		let out: Vec<_> = (0..9600)
+			.map(|i| shaper.process(&d, (2. * PI * 5000. * i as f64 / 48000.).sin()))
+			.collect();
+		let aliases = (1..24).filter(|k| k % 5 != 0).map(|k| level(&out[4800..], k as f64 * 1000.).powi(2));
+		(level(&out[4800..], 5000.), aliases.sum::<Sample>().sqrt())
+	};
+	let (plain, plain_alias) = clipped(Oversampling::X1);
+	let mut last = plain_alias;
+	for &oversampling in &[Oversampling::X2, Oversampling::X4, Oversampling::X8] {
+		let (fundamental, alias) = clipped(oversampling);
+		assert!(alias < last, "{:?}: {}", oversampling, alias);
+		assert!((fundamental - plain).abs() < 0.05 * plain);
+		last = alias;
+	}
+	assert!(last < plain_alias / 10.);
+}
+
+#[test]
+fn test_waveshaper() {
+	use std::f64::consts::PI;
+	let mut shaper = Waveshaper::new();
+	shaper.set_sample_rate(48000);
+	assert_eq!(shaper.get(shaper.find("shape").unwrap()), 1.);
+	shaper.set(shaper.find("shape").unwrap(), 2.);
+	assert_eq!(shaper.distortion.shape, Shape::HardClip);
+	shaper.set(shaper.find("oversampling").unwrap(), 3.);
+	assert_eq!(shaper.distortion.oversampling, Oversampling::X8);
+
+	// the tone control darkens the result
+	let bright: Vec<_> = (0..9600).map(|i| shaper.process([(2. * PI * 500. * i as f64 / 48000.).sin(); 2])[0]).collect();
+	shaper.reset();
+	shaper.set(shaper.find("tone").unwrap(), 0.3);
+	let dark: Vec<_> = (0..9600).map(|i| shaper.process([(2. * PI * 500. * i as f64 / 48000.).sin(); 2])[0]).collect();
+	assert!(level(&dark[4800..], 4500.) < level(&bright[4800..], 4500.) / 4.);
+	assert!(level(&dark[4800..], 500.) > level(&bright[4800..], 500.) * 0.8);
+
+	shaper.set(shaper.find("shape").unwrap(), 0.);
+	assert_eq!(shaper.process([0.25, -0.5]), [0.25, -0.5]);
+}
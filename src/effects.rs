@@ -0,0 +1,477 @@
+use std::f64::consts::{PI, SQRT_2};
+
+use super::types::{SampleRated, Generator, SampleRate, Sample, Frequency, MidiDispatcher, Tempo};
+use super::lfo::Rate;
+use super::tempo::{DIVISIONS, ClockFollower};
+use super::macros::{Macro, Macros, MACROS};
+
+/// Describes one of an effect's parameters, for editors and controller
+/// mappings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Param {
+	pub name: &'static str,
+	pub min: f64,
+	pub max: f64,
+	pub default: f64,
+}
+
+/// A stereo processor on the master bus. `process` returns the fully wet
+/// signal; the `Chain` takes care of bypass and the wet/dry mix.
+pub trait Effect: SampleRated + Send + Sync {
+	fn name(&self) -> &'static str;
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2];
+	fn params(&self) -> &'static [Param];
+	fn get(&self, param: usize) -> f64;
+	/// Values are clamped to the parameter's range.
+	fn set(&mut self, param: usize, value: f64);
+	/// Clears any internal state, such as delay lines.
+	fn reset(&mut self) {}
+	/// For effects with tempo synced times.
+	fn set_bpm(&mut self, _bpm: f64) {}
+
+	fn find(&self, name: &str) -> Option<usize> {
+		self.params().iter().position(|param| param.name == name)
+	}
+}
+
+/// Clamps `value` to the range of `params[param]`.
+pub fn clamp(params: &[Param], param: usize, value: f64) -> f64 {
+	value.max(params[param].min).min(params[param].max)
+}
+
+/// The phase of a modulation effect's sweep, from 0 to 1, at a rate in Hz
+/// or synced to the tempo. Effects keep these as their "rate", "sync" and
+/// "division" parameters.
+pub struct Sweep {
+	pub hz: Frequency,
+	pub sync: bool,
+	/// Index into `DIVISIONS`.
+	pub division: usize,
+	pub bpm: f64,
+	phase: f64,
+	sample_rate: Frequency,
+}
+
+impl SampleRated for Sweep {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Frequency;
+	}
+}
+
+impl Sweep {
+	pub fn new(hz: Frequency) -> Sweep {
+		Sweep {
+			hz,
+			sync: false,
+			division: 0,
+			bpm: 120.,
+			phase: 0.,
+			sample_rate: 0.,
+		}
+	}
+	pub fn rate(&self) -> Rate {
+		if self.sync { Rate::Tempo(DIVISIONS[self.division]) } else { Rate::Hz(self.hz) }
+	}
+	pub fn frequency(&self) -> Frequency {
+		self.rate().frequency(self.bpm)
+	}
+	/// Returns the phase, then moves it on at `freq`.
+	pub fn run(&mut self, freq: Frequency) -> f64 {
+		let phase = self.phase;
+		if self.sample_rate > 0. {
+			self.phase += freq / self.sample_rate;
+			self.phase -= self.phase.floor();
+		}
+		phase
+	}
+	pub fn reset(&mut self) {
+		self.phase = 0.;
+	}
+	/// Reads the sweep's part of an effect's parameters, which come first.
+	pub fn get(&self, param: usize) -> f64 {
+		match param {
+			0 => self.hz,
+			1 => if self.sync { 1. } else { 0. },
+			_ => self.division as f64,
+		}
+	}
+	pub fn set(&mut self, param: usize, value: f64) {
+		match param {
+			0 => self.hz = value,
+			1 => self.sync = value >= 0.5,
+			_ => self.division = value.round() as usize,
+		}
+	}
+}
+
+struct Slot {
+	effect: Box<dyn Effect>,
+	bypass: bool,
+	mix: Sample,
+}
+
+/// Effects run one after the other, each of which can be bypassed and
+/// mixed with what comes into it.
+pub struct Effects {
+	slots: Vec<Slot>,
+	sample_rate: SampleRate,
+}
+
+impl Effects {
+	pub fn new() -> Effects {
+		Effects {
+			slots: Vec::new(),
+			sample_rate: 0,
+		}
+	}
+	/// Adds an effect at the end of the chain, fully wet, returning its
+	/// position.
+	pub fn push(&mut self, effect: Box<dyn Effect>) -> usize {
+		let i = self.slots.len();
+		self.insert(i, effect);
+		i
+	}
+	pub fn insert(&mut self, i: usize, mut effect: Box<dyn Effect>) {
+		if self.sample_rate > 0 {
+			effect.set_sample_rate(self.sample_rate);
+		}
+		self.slots.insert(i, Slot { effect, bypass: false, mix: 1. });
+	}
+	pub fn remove(&mut self, i: usize) -> Box<dyn Effect> {
+		self.slots.remove(i).effect
+	}
+	/// Moves the effect at `from` so that it ends up at `to`.
+	pub fn move_effect(&mut self, from: usize, to: usize) {
+		let slot = self.slots.remove(from);
+		self.slots.insert(to, slot);
+	}
+	pub fn len(&self) -> usize {
+		self.slots.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.slots.is_empty()
+	}
+	pub fn effect(&self, i: usize) -> &dyn Effect {
+		self.slots[i].effect.as_ref()
+	}
+	pub fn effect_mut(&mut self, i: usize) -> &mut dyn Effect {
+		self.slots[i].effect.as_mut()
+	}
+	pub fn bypass(&self, i: usize) -> bool {
+		self.slots[i].bypass
+	}
+	pub fn set_bypass(&mut self, i: usize, bypass: bool) {
+		self.slots[i].bypass = bypass;
+	}
+	pub fn mix(&self, i: usize) -> Sample {
+		self.slots[i].mix
+	}
+	/// From 0, dry, to 1, wet only.
+	pub fn set_mix(&mut self, i: usize, mix: Sample) {
+		self.slots[i].mix = mix.max(0.).min(1.);
+	}
+	pub fn set_bpm(&mut self, bpm: f64) {
+		for slot in self.slots.iter_mut() {
+			slot.effect.set_bpm(bpm);
+		}
+	}
+	/// Sets the effect parameters the knobs are mapped to. Mappings to a
+	/// missing slot or parameter are ignored, and of several on the same
+	/// parameter the last wins.
+	pub fn apply_macros(&mut self, macros: &[Macro]) {
+		for knob in macros.iter() {
+			for (slot, param, value) in knob.effect_values() {
+				if let Some(slot) = self.slots.get_mut(slot) {
+					if param < slot.effect.params().len() {
+						slot.effect.set(param, value);
+					}
+				}
+			}
+		}
+	}
+	pub fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		let mut out = input;
+		for slot in self.slots.iter_mut().filter(|slot| !slot.bypass) {
+			let wet = slot.effect.process(out);
+			let dry = 1. - slot.mix;
+			out = [
+				out[0] * dry + wet[0] * slot.mix,
+				out[1] * dry + wet[1] * slot.mix,
+			];
+		}
+		out
+	}
+}
+
+impl SampleRated for Effects {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate;
+		for slot in self.slots.iter_mut() {
+			slot.effect.set_sample_rate(sample_rate);
+		}
+	}
+}
+
+/// Runs a generator's output through effects in order, before it reaches
+/// the audio device. MIDI clock coming in sets the tempo of the effects
+/// and of the generator, and the
+/// generator's macro knobs set the effect parameters mapped to them
+/// whenever they change.
+pub struct Chain<G> {
+	pub inner: G,
+	pub effects: Effects,
+	macros: Option<[Macro; MACROS]>,
+	clock: ClockFollower,
+	clk: u64,
+	sample_rate: f64,
+}
+
+impl<G> Chain<G> {
+	pub fn new(inner: G) -> Chain<G> {
+		Chain {
+			inner: inner,
+			effects: Effects::new(),
+			macros: None,
+			clock: ClockFollower::new(),
+			clk: 0,
+			sample_rate: 0.,
+		}
+	}
+}
+
+impl<G: SampleRated> SampleRated for Chain<G> {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as f64;
+		self.clock.reset();
+		self.effects.set_sample_rate(sample_rate);
+		self.inner.set_sample_rate(sample_rate);
+	}
+}
+
+impl<G: Generator + Macros> Generator for Chain<G> {
+	fn generate(&mut self) -> [f32; 2] {
+		self.clk += 1;
+		if let Some(macros) = self.inner.macros() {
+			if self.macros.as_ref() != Some(macros) {
+				self.effects.apply_macros(macros);
+				self.macros = Some(*macros);
+			}
+		}
+		let [l, r] = self.inner.generate();
+		let [l, r] = self.effects.process([l as Sample, r as Sample]);
+		[l as f32, r as f32]
+	}
+}
+
+impl<G: Tempo> Tempo for Chain<G> {
+	fn set_bpm(&mut self, bpm: f64) {
+		self.effects.set_bpm(bpm);
+		self.inner.set_bpm(bpm);
+	}
+}
+
+impl<G: MidiDispatcher + Tempo> MidiDispatcher for Chain<G> {
+	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
+		use midistream::*;
+		match msg {
+			Msg::Simple(SimpleMsg::TimingClock) => {
+				if let Some(bpm) = self.clock.tick(self.clk, self.sample_rate) {
+					self.set_bpm(bpm);
+				}
+			},
+			Msg::Simple(SimpleMsg::Start) | Msg::Simple(SimpleMsg::Stop) => self.clock.reset(),
+			_ => {},
+		}
+		self.inner.dispatch_midi_in(msg);
+	}
+}
+
+const PAN_PARAMS: [Param; 2] = [
+	Param { name: "gain", min: 0., max: 2., default: 1. },
+	Param { name: "pan", min: -1., max: 1., default: 0. },
+];
+
+/// Constant power pan law, unity in the centre, with `pan` running from -1
+/// (left) to 1 (right). Shared by `Pan`, the voices and multitimbral parts.
+pub fn pan_gains(pan: Sample) -> [Sample; 2] {
+	let angle = (pan.max(-1.).min(1.) + 1.) * PI / 4.;
+	[angle.cos() * SQRT_2, angle.sin() * SQRT_2]
+}
+
+/// Master gain and constant power panning, unity in the centre.
+pub struct Pan {
+	gain: Sample,
+	pan: Sample,
+}
+
+impl Pan {
+	pub fn new() -> Pan {
+		Pan { gain: 1., pan: 0. }
+	}
+}
+
+impl SampleRated for Pan {
+	fn set_sample_rate(&mut self, _sample_rate: SampleRate) {}
+}
+
+impl Effect for Pan {
+	fn name(&self) -> &'static str {
+		"Pan"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		let [l, r] = pan_gains(self.pan);
+		[input[0] * self.gain * l, input[1] * self.gain * r]
+	}
+	fn params(&self) -> &'static [Param] {
+		&PAN_PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0 => self.gain,
+			1 => self.pan,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		match param {
+			0 => self.gain = clamp(&PAN_PARAMS, param, value),
+			1 => self.pan = clamp(&PAN_PARAMS, param, value),
+			_ => {},
+		}
+	}
+}
+
+#[cfg(test)]
+struct Constant(Sample);
+
+#[cfg(test)]
+impl Generator for Constant {
+	fn generate(&mut self) -> [f32; 2] {
+		[self.0 as f32; 2]
+	}
+}
+
+#[cfg(test)]
+impl SampleRated for Constant {
+	fn set_sample_rate(&mut self, _sample_rate: SampleRate) {}
+}
+
+#[cfg(test)]
+impl MidiDispatcher for Constant {
+	fn dispatch_midi_in(&mut self, _msg: &midistream::Msg) {}
+}
+
+#[cfg(test)]
+impl Macros for Constant {}
+
+#[cfg(test)]
+impl Tempo for Constant {}
+
+#[test]
+fn test_chain() {
+	let mut chain = Chain::new(Constant(0.5));
+	assert_eq!(chain.generate(), [0.5, 0.5]);
+
+	let gain = chain.effects.push(Box::new(Pan::new()));
+	chain.effects.effect_mut(gain).set(0, 2.);
+	let pan = chain.effects.push(Box::new(Pan::new()));
+	let hard_right = chain.effects.effect(pan).find("pan").unwrap();
+	chain.effects.effect_mut(pan).set(hard_right, 5.);
+	assert_eq!(chain.effects.effect(pan).get(hard_right), 1.);
+	let [l, r] = chain.generate();
+	assert!(l.abs() < 1e-6);
+	assert!((r - SQRT_2 as f32).abs() < 1e-6);
+
+	// half wet leaves half of the left channel
+	chain.effects.set_mix(pan, 0.5);
+	let [l, _] = chain.generate();
+	assert!((l - 0.5).abs() < 1e-6);
+
+	chain.effects.set_bypass(gain, true);
+	let [l, _] = chain.generate();
+	assert!((l - 0.25).abs() < 1e-6);
+
+	chain.effects.move_effect(pan, 0);
+	assert_eq!(chain.effects.mix(0), 0.5);
+	assert!(chain.effects.bypass(1));
+	assert_eq!(chain.effects.remove(1).name(), "Pan");
+	assert_eq!(chain.effects.len(), 1);
+}
+
+/// Only keeps the tempo it is given, as its one parameter.
+#[cfg(test)]
+pub(crate) struct Metronome(pub f64);
+
+#[cfg(test)]
+impl SampleRated for Metronome {
+	fn set_sample_rate(&mut self, _sample_rate: SampleRate) {}
+}
+
+#[cfg(test)]
+impl Effect for Metronome {
+	fn name(&self) -> &'static str {
+		"Metronome"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		input
+	}
+	fn params(&self) -> &'static [Param] {
+		&[]
+	}
+	fn get(&self, _param: usize) -> f64 {
+		self.0
+	}
+	fn set(&mut self, _param: usize, _value: f64) {}
+	fn set_bpm(&mut self, bpm: f64) {
+		self.0 = bpm;
+	}
+}
+
+#[test]
+fn test_chain_clock() {
+	use midistream::SimpleMsg;
+	let mut chain = Chain::new(Constant(0.));
+	let metronome = chain.effects.push(Box::new(Metronome(120.)));
+	chain.set_sample_rate(1000);
+	// a tick every 25 samples is a beat every 0.6 s
+	chain.dispatch_midi_in(&SimpleMsg::Start.into());
+	for _ in 0..48 {
+		chain.dispatch_midi_in(&SimpleMsg::TimingClock.into());
+		for _ in 0..25 { chain.generate(); }
+	}
+	assert!((chain.effects.effect(metronome).get(0) - 100.).abs() < 1e-9);
+
+	// speeding up, over the next beat
+	for _ in 0..25 {
+		chain.dispatch_midi_in(&SimpleMsg::TimingClock.into());
+		for _ in 0..20 { chain.generate(); }
+	}
+	assert!((chain.effects.effect(metronome).get(0) - 125.).abs() < 1e-9);
+}
+
+#[test]
+fn test_chain_macros() {
+	use super::oscillator::{Oscillator, Waveforms};
+	use super::reverb::Reverb;
+	use super::macros::Mapping;
+	use super::matrix::Destination;
+	use super::patch::Patch;
+	let mut chain = Chain::new(Oscillator::new(Waveforms::Saw));
+	chain.set_sample_rate(48000);
+	chain.effects.push(Box::new(Pan::new()));
+	let reverb = chain.effects.push(Box::new(Reverb::new()));
+	let size = chain.effects.effect(reverb).find("size").unwrap();
+
+	// one knob opens the filter and grows the reverb
+	let mut patch = Patch::new();
+	patch.macros[0].map(Mapping::new(Destination::Cutoff, 0., 24.));
+	patch.macros[0].map(Mapping::effect(reverb, size, 0.2, 0.9));
+	chain.inner.set_patch(patch);
+	chain.generate();
+	assert_eq!(chain.effects.effect(reverb).get(size), 0.2);
+
+	chain.inner.set_macro(0, 1.);
+	chain.generate();
+	assert!((chain.effects.effect(reverb).get(size) - 0.9).abs() < 1e-12);
+	assert_eq!(chain.inner.patch().macro_modulation().cutoff, 24.);
+}
@@ -0,0 +1,423 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::{SampleRated, SampleRate, Sample, Frequency, Seconds};
+use super::adsr::{ADSR, Gate, Shape, Retrigger, FADE_TIME};
+
+pub const MAX_SEGMENTS: usize = 16;
+
+/// One stage of an `Envelope`: move to `level` in `time`, along `shape`.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Segment {
+	pub time: Seconds,
+	pub level: Sample,
+	pub shape: Shape,
+}
+
+impl Segment {
+	pub fn new(time: Seconds, level: Sample) -> Segment {
+		Segment { time, level, shape: Shape::Linear }
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Stage {
+	Off,
+	Fade,
+	Run(usize),
+	Sustain,
+}
+
+/// A multi-stage envelope through up to `MAX_SEGMENTS` breakpoints (MSEG),
+/// starting from wherever the level is when the gate opens. A first
+/// segment down to zero is a delay, which holds a sounding level rather
+/// than falling to silence.
+///
+/// While the gate is open the envelope stops at the end of the sustain
+/// segment, or cycles from `loop_end` back to `loop_start`. Closing the
+/// gate jumps to the segment after the sustain point; without one, it only
+/// lets a loop run on to the end.
+#[derive(Copy, Clone, Debug)]
+pub struct Envelope {
+	segments: [Segment; MAX_SEGMENTS],
+	len: usize,
+	sustain: Option<usize>,
+	loop_points: Option<(usize, usize)>,
+	retrigger: Retrigger,
+	scale_a: f64, scale_d: f64, scale_r: f64,
+	sample_rate: Frequency,
+	stage: Stage,
+	gate: bool,
+	val: Sample,
+	start: Sample,
+	target: Sample,
+	shape: Shape,
+	phase: f64,
+	incr: f64,
+}
+
+impl SampleRated for Envelope {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Frequency;
+	}
+}
+
+impl Envelope {
+	/// A DAHDSR with the same times and sustain level as `ADSR::new`.
+	pub fn new() -> Envelope {
+		Envelope::dahdsr(0., 0.1, 0., 1.0, 0.75, 0.25)
+	}
+	/// Delay, attack, hold, decay, sustain and release, with times in seconds.
+	/// The delay holds at zero, or where the level was when retriggered.
+	pub fn dahdsr(delay: Seconds, attack: Seconds, hold: Seconds, decay: Seconds, sustain: Sample, release: Seconds) -> Envelope {
+		Envelope::breakpoints(&[
+			Segment::new(delay, 0.),
+			Segment::new(attack, 1.),
+			Segment::new(hold, 1.),
+			Segment::new(decay, sustain),
+			Segment::new(release, 0.),
+		], Some(3), None)
+	}
+	/// `sustain` and the loop points are segment indices; the loop includes
+	/// both ends.
+	pub fn breakpoints(segments: &[Segment], sustain: Option<usize>, loop_points: Option<(usize, usize)>) -> Envelope {
+		let mut env = Envelope {
+			segments: [Segment::new(0., 0.); MAX_SEGMENTS],
+			len: 0,
+			sustain: None,
+			loop_points: None,
+			retrigger: Retrigger::Continue,
+			scale_a: 1., scale_d: 1., scale_r: 1.,
+			sample_rate: 0.,
+			stage: Stage::Off,
+			gate: false,
+			val: 0.,
+			start: 0.,
+			target: 0.,
+			shape: Shape::Linear,
+			phase: 0.,
+			incr: 0.,
+		};
+		env.set_segments(segments, sustain, loop_points);
+		env
+	}
+	/// Replaces the segments. A running envelope picks them up at its next
+	/// segment; indices past the end are ignored.
+	pub fn set_segments(&mut self, segments: &[Segment], sustain: Option<usize>, loop_points: Option<(usize, usize)>) {
+		self.len = segments.len().min(MAX_SEGMENTS);
+		self.segments[..self.len].copy_from_slice(&segments[..self.len]);
+		self.sustain = sustain.filter(|&s| s < self.len);
+		self.loop_points = loop_points.filter(|&(s, e)| s <= e && e < self.len);
+		if let Stage::Run(i) = self.stage {
+			if i >= self.len {
+				self.stage = Stage::Off;
+			}
+		}
+	}
+	pub fn segments(&self) -> &[Segment] {
+		&self.segments[..self.len]
+	}
+	pub fn set_retrigger(&mut self, retrigger: Retrigger) {
+		self.retrigger = retrigger;
+	}
+	/// Like `ADSR::scale_times`: up to the sustain point, rising and level
+	/// segments take the attack scale and falling ones the decay scale;
+	/// segments after it take the release scale.
+	pub fn scale_times(&mut self, attack: f64, decay: f64, release: f64) {
+		self.scale_a = attack;
+		self.scale_d = decay;
+		self.scale_r = release;
+	}
+	pub fn value(&self) -> Sample {
+		self.val
+	}
+	pub fn is_off(&self) -> bool {
+		self.stage == Stage::Off
+	}
+	fn scale(&self, i: usize) -> f64 {
+		match self.sustain {
+			Some(s) if i > s => self.scale_r,
+			_ => {
+				let prev = if i == 0 { self.start } else { self.segments[i - 1].level };
+				if self.segments[i].level >= prev { self.scale_a } else { self.scale_d }
+			},
+		}
+	}
+	fn begin(&mut self, stage: Stage, time: Seconds, target: Sample, shape: Shape) {
+		self.stage = stage;
+		self.start = self.val;
+		self.target = target;
+		self.shape = shape;
+		self.phase = 0.;
+		// zero length segments finish on their first sample
+		self.incr = 1. / (time * self.sample_rate);
+	}
+	fn enter(&mut self, i: usize) {
+		if i >= self.len {
+			self.stage = Stage::Off;
+			return;
+		}
+		let seg = self.segments[i];
+		self.start = self.val;
+		let time = seg.time * self.scale(i);
+		self.begin(Stage::Run(i), time, seg.level, seg.shape);
+	}
+	/// Enters the first segment as the gate opens.
+	fn restart(&mut self) {
+		if self.len > 0 && self.segments[0].level == 0. && self.val > 0. {
+			let seg = self.segments[0];
+			self.start = self.val;
+			let time = seg.time * self.scale(0);
+			self.begin(Stage::Run(0), time, self.val, seg.shape);
+		} else {
+			self.enter(0);
+		}
+	}
+	fn next(&mut self, i: usize) {
+		if self.gate {
+			if self.sustain == Some(i) {
+				self.stage = Stage::Sustain;
+				return;
+			}
+			if let Some((start, end)) = self.loop_points {
+				if end == i {
+					self.enter(start);
+					return;
+				}
+			}
+		}
+		self.enter(i + 1);
+	}
+	pub fn run(&mut self) -> Sample {
+		match self.stage {
+			Stage::Off | Stage::Sustain => return self.val,
+			_ => {},
+		}
+		self.phase += self.incr;
+		if self.phase < 1. {
+			self.val = self.start + (self.target - self.start) * self.shape.apply(self.phase);
+			return self.val;
+		}
+		self.val = self.target;
+		match self.stage {
+			Stage::Fade => self.enter(0),
+			Stage::Run(i) => self.next(i),
+			_ => {},
+		}
+		self.val
+	}
+}
+
+impl Gate for Envelope {
+	fn gate_open(&mut self) {
+		let releasing = !self.gate;
+		self.gate = true;
+		match (self.retrigger, self.stage) {
+			(_, Stage::Off) => self.enter(0),
+			(Retrigger::Reset, _) if self.val > 0. => {
+				self.begin(Stage::Fade, FADE_TIME, 0., Shape::Linear);
+			},
+			(Retrigger::Legato, _) => {
+				// back to the sustain level, the way `ADSR` re-enters decay
+				if let (true, Some(s)) = (releasing, self.sustain) {
+					self.enter(s);
+				}
+			},
+			_ => self.restart(),
+		}
+	}
+	fn gate_close(&mut self) {
+		self.gate = false;
+		if let Some(s) = self.sustain {
+			match self.stage {
+				Stage::Run(i) if i > s => {},
+				Stage::Off => {},
+				_ => self.enter(s + 1),
+			}
+		}
+	}
+}
+
+/// Envelope settings, part of the `Patch`: an `ADSR` with its times in
+/// seconds and the shape of each stage, or breakpoints for an `Envelope`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Contour {
+	Adsr {
+		attack: Seconds,
+		decay: Seconds,
+		sustain: Sample,
+		release: Seconds,
+		shapes: [Shape; 3],
+	},
+	Mseg {
+		segments: [Segment; MAX_SEGMENTS],
+		len: usize,
+		sustain: Option<usize>,
+		loop_points: Option<(usize, usize)>,
+	},
+}
+
+impl Contour {
+	/// The same times and sustain level as `ADSR::new`.
+	pub fn new() -> Contour {
+		Contour::Adsr {
+			attack: 0.1,
+			decay: 1.0,
+			sustain: 0.75,
+			release: 0.25,
+			shapes: [Shape::Linear; 3],
+		}
+	}
+	/// Breakpoints as for `Envelope::breakpoints`, past `MAX_SEGMENTS` dropped.
+	pub fn mseg(segments: &[Segment], sustain: Option<usize>, loop_points: Option<(usize, usize)>) -> Contour {
+		let len = segments.len().min(MAX_SEGMENTS);
+		let mut all = [Segment::new(0., 0.); MAX_SEGMENTS];
+		all[..len].copy_from_slice(&segments[..len]);
+		Contour::Mseg { segments: all, len, sustain, loop_points }
+	}
+}
+
+/// A voice's envelope, running whichever kind its `Contour` asks for.
+#[derive(Copy, Clone, Debug)]
+pub struct ContourState {
+	adsr: ADSR,
+	mseg: Envelope,
+	is_mseg: bool,
+}
+
+impl SampleRated for ContourState {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.adsr.set_sample_rate(sample_rate);
+		self.mseg.set_sample_rate(sample_rate);
+	}
+}
+
+impl ContourState {
+	pub fn new() -> ContourState {
+		ContourState {
+			adsr: ADSR::new(),
+			mseg: Envelope::new(),
+			is_mseg: false,
+		}
+	}
+	/// Takes up new settings, at note on. Changing kind starts the other
+	/// envelope from silence.
+	pub fn set_contour(&mut self, contour: &Contour) {
+		match *contour {
+			Contour::Adsr { attack, decay, sustain, release, shapes } => {
+				self.is_mseg = false;
+				self.adsr.set_times(attack, decay, sustain, release);
+				self.adsr.set_shapes(shapes[0], shapes[1], shapes[2]);
+			},
+			Contour::Mseg { segments, len, sustain, loop_points } => {
+				self.is_mseg = true;
+				self.mseg.set_segments(&segments[..len.min(MAX_SEGMENTS)], sustain, loop_points);
+			},
+		}
+	}
+	pub fn set_retrigger(&mut self, retrigger: Retrigger) {
+		self.adsr.set_retrigger(retrigger);
+		self.mseg.set_retrigger(retrigger);
+	}
+	pub fn scale_times(&mut self, attack: f64, decay: f64, release: f64) {
+		self.adsr.scale_times(attack, decay, release);
+		self.mseg.scale_times(attack, decay, release);
+	}
+	pub fn value(&self) -> Sample {
+		if self.is_mseg { self.mseg.value() } else { self.adsr.value() }
+	}
+	pub fn is_off(&self) -> bool {
+		if self.is_mseg { self.mseg.is_off() } else { self.adsr.is_off() }
+	}
+	pub fn run(&mut self) -> Sample {
+		if self.is_mseg { self.mseg.run() } else { self.adsr.run() }
+	}
+}
+
+impl Gate for ContourState {
+	fn gate_open(&mut self) {
+		if self.is_mseg { self.mseg.gate_open() } else { self.adsr.gate_open() }
+	}
+	fn gate_close(&mut self) {
+		if self.is_mseg { self.mseg.gate_close() } else { self.adsr.gate_close() }
+	}
+}
+
+#[cfg(test)]
+fn run_for(env: &mut Envelope, samples: usize) -> Sample {
+	for _ in 0..samples {
+		env.run();
+	}
+	env.value()
+}
+
+#[test]
+fn test_dahdsr() {
+	// 1024 Hz and power of two times, so the segments land on exact samples
+	let mut env = Envelope::dahdsr(0.0625, 0.125, 0.0625, 0.125, 0.5, 0.125);
+	env.set_sample_rate(1024);
+	env.gate_open();
+	assert_eq!(run_for(&mut env, 64), 0.);
+	assert_eq!(run_for(&mut env, 64), 0.5);
+	assert_eq!(run_for(&mut env, 64), 1.);
+	assert_eq!(run_for(&mut env, 64), 1.);
+	assert_eq!(run_for(&mut env, 64), 0.75);
+	assert_eq!(run_for(&mut env, 64), 0.5);
+	assert_eq!(env.stage, Stage::Sustain);
+	assert_eq!(run_for(&mut env, 1000), 0.5);
+
+	env.gate_close();
+	assert_eq!(run_for(&mut env, 64), 0.25);
+	assert_eq!(run_for(&mut env, 64), 0.);
+	assert!(env.is_off());
+}
+
+#[test]
+fn test_dahdsr_retrigger() {
+	for &delay in &[0., 0.0625] {
+		let mut env = Envelope::dahdsr(delay, 0.125, 0.0625, 0.125, 0.5, 0.125);
+		env.set_sample_rate(1024);
+		env.gate_open();
+		assert_eq!(run_for(&mut env, 1024), 0.5);
+		// struck again while sustaining, the delay holds the level and the
+		// attack rises from it
+		env.gate_open();
+		let mut last = env.value();
+		for _ in 0..(64 + 128) {
+			let v = env.run();
+			assert!(v >= last && v - last < 0.01, "delay {}: {} after {}", delay, v, last);
+			last = v;
+		}
+		assert_eq!(last, 1.);
+	}
+}
+
+#[test]
+fn test_mseg_loop() {
+	let time = 0.0078125;
+	let mut env = Envelope::breakpoints(&[
+		Segment::new(time, 1.),
+		Segment::new(time, 0.5),
+		Segment::new(time, 1.),
+		Segment { shape: Shape::Exponential, ..Segment::new(time, 0.) },
+	], None, Some((1, 2)));
+	env.set_sample_rate(1024);
+	env.gate_open();
+	assert_eq!(run_for(&mut env, 8), 1.);
+	// cycles between 0.5 and 1 while the gate is open
+	for _ in 0..5 {
+		assert_eq!(run_for(&mut env, 8), 0.5);
+		assert_eq!(run_for(&mut env, 8), 1.);
+	}
+	assert_eq!(env.stage, Stage::Run(1));
+
+	// closing the gate lets it finish the loop, then fall to zero
+	env.gate_close();
+	assert_eq!(run_for(&mut env, 16), 1.);
+	assert!(run_for(&mut env, 4) < 0.5, "the exponential shape falls fast at first");
+	assert_eq!(run_for(&mut env, 4), 0.);
+	assert!(env.is_off());
+
+	env.set_retrigger(Retrigger::Reset);
+	env.gate_open();
+	assert_eq!(env.stage, Stage::Run(0));
+}
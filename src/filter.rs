@@ -0,0 +1,338 @@
+use std::f64::consts::PI;
+use serde::{Serialize, Deserialize};
+
+use super::types::{SampleRated, SampleRate, Sample, Frequency, Semitones};
+use super::formant::VoiceType;
+
+const MIN_CUTOFF: Frequency = 20.;
+/// Fraction of the sample rate the cutoff is held below, short of Nyquist
+/// where the prewarped coefficient blows up.
+const MAX_CUTOFF: f64 = 0.45;
+const CENTER_KEY: i8 = 60;
+/// Damping left at full resonance, which keeps the SVF from growing
+/// without bound, with a gain of at most 1/`MIN_DAMPING` at the cutoff.
+const MIN_DAMPING: f64 = 0.02;
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Mode {
+	Lowpass,
+	Highpass,
+	Bandpass,
+	Notch,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Slope {
+	Db12,
+	Db24,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Kind {
+	Off,
+	Svf(Mode),
+	Ladder(Slope),
+	Formant(VoiceType),
+}
+
+/// Per-voice filter settings, part of the `Patch`.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Filter {
+	pub kind: Kind,
+	pub cutoff: Frequency,
+	/// 0 to 1, self-oscillating at the top.
+	pub resonance: Sample,
+	/// How far the filter envelope moves the cutoff at full level.
+	pub env_amount: Semitones,
+	/// Follows the key from middle C; 1 tracks the pitch exactly.
+	pub key_track: f64,
+	/// Cutoff offset at full velocity.
+	pub velocity: Semitones,
+	/// Gain into the ladder's saturating input stage; 1 is nearly clean.
+	pub drive: f64,
+	/// Position through the vowels of a formant filter, see `formant::Formant`.
+	pub vowel: f64,
+	/// How far the filter envelope moves `vowel` at full level.
+	pub vowel_env: f64,
+}
+
+impl Filter {
+	pub fn new() -> Filter {
+		Filter {
+			kind: Kind::Off,
+			cutoff: 2000.,
+			resonance: 0.,
+			env_amount: 0.,
+			key_track: 0.,
+			velocity: 0.,
+			drive: 1.,
+			vowel: 0.,
+			vowel_env: 0.,
+		}
+	}
+	/// The cutoff for a voice, with `offset` semitones of further modulation.
+	pub fn voice_cutoff(&self, key: i8, env: Sample, velocity: Sample, offset: Semitones) -> Frequency {
+		let semitones = self.env_amount * env
+			+ self.key_track * (key - CENTER_KEY) as f64
+			+ self.velocity * velocity
+			+ offset;
+		self.cutoff * (2.0 as f64).powf(semitones / 12.)
+	}
+}
+
+/// A topology-preserving transform state-variable filter, which keeps its
+/// state meaningful when the cutoff moves every sample.
+#[derive(Clone, Copy, Debug)]
+pub struct Svf {
+	mode: Mode,
+	ic1eq: Sample,
+	ic2eq: Sample,
+	k: f64,
+	a1: f64, a2: f64, a3: f64,
+	sample_rate: Frequency,
+}
+
+impl SampleRated for Svf {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Frequency;
+	}
+}
+
+impl Svf {
+	pub fn new() -> Svf {
+		Svf {
+			mode: Mode::Lowpass,
+			ic1eq: 0., ic2eq: 0.,
+			k: 2.,
+			a1: 1., a2: 0., a3: 0.,
+			sample_rate: 0.,
+		}
+	}
+	pub fn set_mode(&mut self, mode: Mode) {
+		self.mode = mode;
+	}
+	pub fn set(&mut self, cutoff: Frequency, resonance: Sample) {
+		self.set_damping(cutoff, (2. * (1. - resonance.max(0.).min(1.))).max(MIN_DAMPING));
+	}
+	/// Sets the cutoff with a bandwidth in Hz rather than a resonance. The
+	/// bandpass output peaks at `cutoff / bandwidth` times the input.
+	pub fn set_bandwidth(&mut self, cutoff: Frequency, bandwidth: Frequency) {
+		self.set_damping(cutoff, bandwidth / cutoff);
+	}
+	fn set_damping(&mut self, cutoff: Frequency, k: f64) {
+		if self.sample_rate == 0. {
+			return;
+		}
+		let cutoff = cutoff.max(MIN_CUTOFF).min(MAX_CUTOFF * self.sample_rate);
+		let g = (PI * cutoff / self.sample_rate).tan();
+		self.k = k;
+		self.a1 = 1. / (1. + g * (g + self.k));
+		self.a2 = g * self.a1;
+		self.a3 = g * self.a2;
+	}
+	pub fn reset(&mut self) {
+		self.ic1eq = 0.;
+		self.ic2eq = 0.;
+	}
+	pub fn process(&mut self, x: Sample) -> Sample {
+		let v3 = x - self.ic2eq;
+		let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+		let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+		self.ic1eq = 2. * v1 - self.ic1eq;
+		self.ic2eq = 2. * v2 - self.ic2eq;
+		match self.mode {
+			Mode::Lowpass  => v2,
+			Mode::Highpass => x - self.k * v1 - v2,
+			Mode::Bandpass => v1,
+			Mode::Notch    => x - self.k * v1,
+		}
+	}
+}
+
+/// Feedback at full resonance, a little past the point of self-oscillation.
+const LADDER_MAX_FEEDBACK: f64 = 4.2;
+
+/// A Moog-style ladder: four one-pole lowpass stages in a feedback loop,
+/// solved without the unit delay in the loop. The input stage saturates,
+/// which sets the drive character and keeps self-oscillation bounded.
+#[derive(Clone, Copy, Debug)]
+pub struct Ladder {
+	slope: Slope,
+	s: [Sample; 4],
+	g: f64,
+	k: f64,
+	drive: f64,
+	sample_rate: Frequency,
+}
+
+impl SampleRated for Ladder {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Frequency;
+	}
+}
+
+impl Ladder {
+	pub fn new() -> Ladder {
+		Ladder {
+			slope: Slope::Db24,
+			s: [0.; 4],
+			g: 0.,
+			k: 0.,
+			drive: 1.,
+			sample_rate: 0.,
+		}
+	}
+	pub fn set_slope(&mut self, slope: Slope) {
+		self.slope = slope;
+	}
+	pub fn set(&mut self, cutoff: Frequency, resonance: Sample, drive: f64) {
+		if self.sample_rate == 0. {
+			return;
+		}
+		let cutoff = cutoff.max(MIN_CUTOFF).min(MAX_CUTOFF * self.sample_rate);
+		self.g = (PI * cutoff / self.sample_rate).tan();
+		self.k = LADDER_MAX_FEEDBACK * resonance.max(0.).min(1.);
+		self.drive = drive.max(0.01);
+	}
+	pub fn reset(&mut self) {
+		self.s = [0.; 4];
+	}
+	pub fn process(&mut self, x: Sample) -> Sample {
+		let g = self.g / (1. + self.g);
+		let b = 1. / (1. + self.g);
+		// the ladder's output as a linear function of its input, G⁴u + S
+		let state = g * g * g * b * self.s[0] + g * g * b * self.s[1] + g * b * self.s[2] + b * self.s[3];
+		let g4 = g * g * g * g;
+		let y = (g4 * x + state) / (1. + self.k * g4);
+		let mut u = (self.drive * (x - self.k * y)).tanh();
+		let mut taps = [0.; 4];
+		for (s, tap) in self.s.iter_mut().zip(taps.iter_mut()) {
+			let v = (u - *s) * g;
+			*tap = v + *s;
+			*s = *tap + v;
+			u = *tap;
+		}
+		match self.slope {
+			Slope::Db12 => taps[1],
+			Slope::Db24 => taps[3],
+		}
+	}
+}
+
+/// Peak level of a sine at `freq` once the filter has settled.
+#[cfg(test)]
+fn response(svf: &mut Svf, freq: Frequency) -> Sample {
+	svf.reset();
+	let mut peak: Sample = 0.;
+	for i in 0..48000 {
+		let y = svf.process((2. * PI * freq * i as f64 / 48000.).sin());
+		if i > 24000 {
+			peak = peak.max(y.abs());
+		}
+	}
+	peak
+}
+
+#[test]
+fn test_svf_modes() {
+	let mut svf = Svf::new();
+	svf.set_sample_rate(48000);
+	svf.set(1000., 0.);
+
+	assert!(response(&mut svf, 100.) > 0.99);
+	assert!(response(&mut svf, 10000.) < 0.02);
+	assert!((response(&mut svf, 1000.) - 0.5).abs() < 0.01, "-6 dB at the cutoff");
+
+	svf.set_mode(Mode::Highpass);
+	assert!(response(&mut svf, 100.) < 0.02);
+	assert!(response(&mut svf, 10000.) > 0.98);
+
+	svf.set_mode(Mode::Notch);
+	assert!(response(&mut svf, 1000.) < 0.01);
+	assert!(response(&mut svf, 100.) > 0.95);
+
+	svf.set_mode(Mode::Bandpass);
+	svf.set(1000., 0.9);
+	assert!(response(&mut svf, 1000.) > 4.);
+}
+
+#[test]
+fn test_svf_modulation() {
+	// sweeping the cutoff at audio rate with high resonance stays bounded
+	let mut svf = Svf::new();
+	svf.set_sample_rate(48000);
+	for i in 0..48000 {
+		let sweep = (i as f64 * 0.05).sin();
+		svf.set(1000. * (2.0 as f64).powf(6. * sweep), 0.95);
+		let y = svf.process(if i % 100 < 50 { 1. } else { -1. });
+		assert!(y.is_finite() && y.abs() < 100.);
+	}
+
+	// full resonance fed right at the cutoff rings loudly, but settles
+	svf.reset();
+	svf.set(1000., 1.);
+	let mut peak: Sample = 0.;
+	for i in 0..96000 {
+		let y = svf.process((2. * PI * 1000. * i as f64 / 48000.).sin());
+		peak = peak.max(y.abs());
+	}
+	assert!(peak.is_finite() && peak <= 1. / MIN_DAMPING + 1e-6, "peak = {}", peak);
+
+	let filter = Filter { env_amount: 24., key_track: 1., velocity: -12., ..Filter::new() };
+	assert_eq!(filter.voice_cutoff(60, 0., 0., 0.), 2000.);
+	assert_eq!(filter.voice_cutoff(72, 0., 0., 0.), 4000.);
+	assert_eq!(filter.voice_cutoff(60, 0.5, 1., 0.), 2000.);
+	assert_eq!(filter.voice_cutoff(60, 0., 0., -12.), 1000.);
+}
+
+#[test]
+fn test_ladder() {
+	let mut ladder = Ladder::new();
+	ladder.set_sample_rate(48000);
+	let response = |ladder: &mut Ladder, freq: Frequency| -> Sample {
+		ladder.reset();
+		let mut peak: Sample = 0.;
+		for i in 0..48000 {
+			let y = ladder.process(0.1 * (2. * PI * freq * i as f64 / 48000.).sin());
+			if i > 24000 {
+				peak = peak.max(y.abs());
+			}
+		}
+		peak / 0.1
+	};
+	ladder.set(1000., 0., 1.);
+	assert!(response(&mut ladder, 50.) > 0.98);
+	let steep = response(&mut ladder, 8000.);
+	assert!(steep < 0.001, "24 dB per octave");
+	ladder.set_slope(Slope::Db12);
+	let gentle = response(&mut ladder, 8000.);
+	assert!(gentle > steep * 10. && gentle < 0.03);
+
+	// at full resonance it rings on by itself, at the cutoff and bounded
+	ladder.set_slope(Slope::Db24);
+	ladder.set(1000., 1., 1.);
+	ladder.reset();
+	ladder.process(1.);
+	let (mut peak, mut crossings, mut last): (Sample, usize, Sample) = (0., 0, 0.);
+	for i in 0..48000 {
+		let y = ladder.process(0.);
+		assert!(y.is_finite() && y.abs() < 2.);
+		if i >= 24000 {
+			peak = peak.max(y.abs());
+			if last < 0. && y >= 0. { crossings += 1; }
+		}
+		last = y;
+	}
+	assert!(peak > 0.1);
+	assert!(crossings > 450 && crossings < 1050, "{} cycles in half a second", crossings);
+
+	// drive saturates loud signals
+	ladder.set(1000., 0., 4.);
+	assert!(response(&mut ladder, 50.) > 3.);
+	ladder.reset();
+	let mut peak: Sample = 0.;
+	for i in 0..48000 {
+		peak = peak.max(ladder.process(10. * (2. * PI * 50. * i as f64 / 48000.).sin()).abs());
+	}
+	assert!(peak <= 1.);
+}
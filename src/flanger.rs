@@ -0,0 +1,163 @@
+use std::f64::consts::PI;
+
+use super::types::{SampleRated, SampleRate, Sample, Seconds};
+use super::effects::{Effect, Param, Sweep, clamp};
+use super::delay::Line;
+
+const MAX_DELAY: Seconds = 0.02;
+
+const PARAMS: [Param; 8] = [
+	Param { name: "rate", min: 0.01, max: 10., default: 0.2 },
+	Param { name: "sync", min: 0., max: 1., default: 0. },
+	Param { name: "division", min: 0., max: 11., default: 0. },
+	Param { name: "delay", min: 0.1, max: 10., default: 3. },
+	Param { name: "depth", min: 0., max: 1., default: 0.7 },
+	Param { name: "feedback", min: -0.95, max: 0.95, default: 0.5 },
+	Param { name: "through_zero", min: 0., max: 1., default: 0. },
+	Param { name: "spread", min: 0., max: 1., default: 0.5 },
+];
+
+/// Stereo flanger: a delay swept by `depth` either side of `delay`
+/// milliseconds. The notches come from mixing it with the dry signal in
+/// the `Chain`, deepest at a mix of 0.5. Through zero, the wet signal has
+/// a second tap of its own held at `delay`, with the sweep subtracted from
+/// it, giving a deep null as the sweep crosses it at full mix. `spread`
+/// sets the right side up to half a cycle apart from the left.
+pub struct Flanger {
+	sweep: Sweep,
+	delay: Seconds,
+	depth: f64,
+	feedback: Sample,
+	through_zero: bool,
+	spread: f64,
+	lines: Vec<Line>,
+	dry: Vec<Line>,
+	sample_rate: f64,
+}
+
+impl Flanger {
+	pub fn new() -> Flanger {
+		let mut sweep = Sweep::new(PARAMS[0].default);
+		sweep.division = PARAMS[2].default as usize;
+		Flanger {
+			sweep,
+			delay: PARAMS[3].default / 1000.,
+			depth: PARAMS[4].default,
+			feedback: PARAMS[5].default,
+			through_zero: false,
+			spread: PARAMS[7].default,
+			lines: (0..2).map(|_| Line::new()).collect(),
+			dry: (0..2).map(|_| Line::new()).collect(),
+			sample_rate: 0.,
+		}
+	}
+}
+
+impl SampleRated for Flanger {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as f64;
+		let len = (MAX_DELAY * self.sample_rate).ceil() as usize + 2;
+		for line in self.lines.iter_mut().chain(self.dry.iter_mut()) {
+			line.resize(len);
+		}
+		self.sweep.set_sample_rate(sample_rate);
+	}
+}
+
+impl Effect for Flanger {
+	fn name(&self) -> &'static str {
+		"Flanger"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		if self.lines[0].is_empty() {
+			return [0.; 2];
+		}
+		let phase = self.sweep.run(self.sweep.frequency());
+		let delay = (self.delay * self.sample_rate).max(1.);
+		let mut out = [0.; 2];
+		for c in 0..2 {
+			let swing = (2. * PI * (phase + c as f64 * self.spread / 2.)).sin();
+			let swept = self.lines[c].read((delay * (1. + self.depth * swing)).max(1.));
+			self.lines[c].write(input[c] + swept * self.feedback);
+			out[c] = if self.through_zero {
+				let dry = self.dry[c].read(delay);
+				self.dry[c].write(input[c]);
+				(dry - swept) / 2.
+			} else {
+				swept
+			};
+		}
+		out
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0..=2 => self.sweep.get(param),
+			3 => self.delay * 1000.,
+			4 => self.depth,
+			5 => self.feedback,
+			6 => if self.through_zero { 1. } else { 0. },
+			7 => self.spread,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		let value = clamp(&PARAMS, param, value);
+		match param {
+			0..=2 => self.sweep.set(param, value),
+			3 => self.delay = value / 1000.,
+			4 => self.depth = value,
+			5 => self.feedback = value,
+			6 => self.through_zero = value >= 0.5,
+			_ => self.spread = value,
+		}
+	}
+	fn reset(&mut self) {
+		for line in self.lines.iter_mut().chain(self.dry.iter_mut()) {
+			line.clear();
+		}
+		self.sweep.reset();
+	}
+	fn set_bpm(&mut self, bpm: f64) {
+		self.sweep.bpm = bpm;
+	}
+}
+
+#[test]
+fn test_flanger() {
+	let mut flanger = Flanger::new();
+	flanger.set(flanger.find("delay").unwrap(), 5.);
+	flanger.set(flanger.find("depth").unwrap(), 0.);
+	flanger.set(flanger.find("feedback").unwrap(), 0.5);
+	flanger.set_sample_rate(1000);
+	let out: Vec<_> = (0..20).map(|i| flanger.process(if i == 0 { [1.; 2] } else { [0.; 2] })[0]).collect();
+	// held still, the click's echoes, halving with the feedback, and none
+	// of the dry click itself
+	assert_eq!(out[5], 1.);
+	assert_eq!(out[10], 0.5);
+	assert_eq!(out[15], 0.25);
+	assert_eq!(out.iter().filter(|&&x| x != 0.).count(), 3);
+
+	// through zero, sitting on the fixed tap, the two cancel completely
+	flanger.reset();
+	flanger.set(flanger.find("feedback").unwrap(), 0.);
+	flanger.set(flanger.find("through_zero").unwrap(), 1.);
+	for i in 0..100 {
+		let [l, r] = flanger.process([(i as Sample * 0.3).sin(); 2]);
+		assert_eq!([l, r], [0.; 2]);
+	}
+
+	// swept, the sweep passes the fixed tap in the middle of its range
+	flanger.reset();
+	flanger.set(flanger.find("depth").unwrap(), 1.);
+	flanger.set(flanger.find("rate").unwrap(), 1.);
+	let out: Vec<_> = (0..1000).map(|i| flanger.process([(i as Sample * 0.3).sin(); 2])).collect();
+	assert!(out[500][0].abs() < 1e-9);
+	assert!(out[250][0].abs() > 0.1);
+	assert!(out[250][1].abs() < 1e-9);
+}
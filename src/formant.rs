@@ -0,0 +1,242 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::{SampleRated, Generator, SampleRate, Sample, Frequency, MidiDispatcher};
+use super::filter::Svf;
+use super::lfo::{Lfo, LfoState};
+
+const FORMANTS: usize = 3;
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum VoiceType {
+	Male,
+	Female,
+}
+
+/// The vowels a, e, i, o and u, in order along the morph.
+pub const VOWELS: usize = 5;
+
+/// Centre frequency, gain and bandwidth of each formant.
+type Vowel = [(Frequency, Sample, Frequency); FORMANTS];
+
+const MALE: [Vowel; VOWELS] = [
+	[(650., 1.0, 80.), (1080., 0.501, 90.), (2650., 0.447, 120.)],
+	[(400., 1.0, 70.), (1700., 0.200, 80.), (2600., 0.251, 100.)],
+	[(290., 1.0, 40.), (1870., 0.178, 90.), (2800., 0.126, 100.)],
+	[(400., 1.0, 40.), ( 800., 0.316, 80.), (2600., 0.251, 100.)],
+	[(350., 1.0, 40.), ( 600., 0.100, 60.), (2700., 0.141, 100.)],
+];
+
+const FEMALE: [Vowel; VOWELS] = [
+	[(800., 1.0, 80.), (1150., 0.501,  90.), (2900., 0.025, 120.)],
+	[(350., 1.0, 60.), (2000., 0.100, 100.), (2800., 0.178, 120.)],
+	[(270., 1.0, 60.), (2140., 0.251,  90.), (2950., 0.050, 100.)],
+	[(450., 1.0, 40.), ( 800., 0.282,  80.), (2830., 0.079, 100.)],
+	[(325., 1.0, 50.), ( 700., 0.158,  60.), (2700., 0.018, 170.)],
+];
+
+/// A bank of bandpass filters at the formants of a sung vowel. `morph`
+/// runs from 0 (a) to 4 (u), blending neighbouring vowels in between.
+#[derive(Clone, Copy, Debug)]
+pub struct Formant {
+	bands: [Svf; FORMANTS],
+	gains: [Sample; FORMANTS],
+}
+
+impl SampleRated for Formant {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		for band in self.bands.iter_mut() {
+			band.set_sample_rate(sample_rate);
+		}
+	}
+}
+
+impl Formant {
+	pub fn new() -> Formant {
+		let mut formant = Formant {
+			bands: [Svf::new(); FORMANTS],
+			gains: [0.; FORMANTS],
+		};
+		for band in formant.bands.iter_mut() {
+			band.set_mode(super::filter::Mode::Bandpass);
+		}
+		formant
+	}
+	pub fn set(&mut self, voice: VoiceType, morph: f64) {
+		let table = match voice {
+			VoiceType::Male   => &MALE,
+			VoiceType::Female => &FEMALE,
+		};
+		let morph = morph.max(0.).min((VOWELS - 1) as f64);
+		let i = (morph as usize).min(VOWELS - 2);
+		let frac = morph - i as f64;
+		for f in 0..FORMANTS {
+			let (f0, g0, b0) = table[i][f];
+			let (f1, g1, b1) = table[i + 1][f];
+			let freq = f0 + (f1 - f0) * frac;
+			let bandwidth = b0 + (b1 - b0) * frac;
+			self.bands[f].set_bandwidth(freq, bandwidth);
+			// normalise each band to unity at its peak
+			self.gains[f] = (g0 + (g1 - g0) * frac) * bandwidth / freq;
+		}
+	}
+	pub fn reset(&mut self) {
+		for band in self.bands.iter_mut() {
+			band.reset();
+		}
+	}
+	pub fn process(&mut self, x: Sample) -> Sample {
+		let mut y = 0.;
+		for (band, gain) in self.bands.iter_mut().zip(self.gains.iter()) {
+			y += band.process(x) * gain;
+		}
+		y
+	}
+}
+
+/// A formant filter over the whole mix of a generator, with the vowel
+/// following channel pressure and an LFO of its own.
+pub struct VowelFilter<G> {
+	pub inner: G,
+	pub enabled: bool,
+	pub voice: VoiceType,
+	pub morph: f64,
+	/// Vowels moved by full channel pressure.
+	pub pressure_depth: f64,
+	/// Sweeps the vowel by `lfo.routing.vowel`; the other destinations
+	/// are ignored.
+	pub lfo: Lfo,
+	lfo_state: LfoState,
+	bpm: f64,
+	pressure: Sample,
+	left: Formant,
+	right: Formant,
+}
+
+impl<G> VowelFilter<G> {
+	pub fn new(inner: G) -> VowelFilter<G> {
+		VowelFilter {
+			inner: inner,
+			enabled: true,
+			voice: VoiceType::Male,
+			morph: 0.,
+			pressure_depth: 0.,
+			lfo: Lfo::new(),
+			lfo_state: LfoState::new(),
+			bpm: 120.,
+			pressure: 0.,
+			left: Formant::new(),
+			right: Formant::new(),
+		}
+	}
+	/// Tempo for an LFO synced to note divisions.
+	pub fn set_bpm(&mut self, bpm: f64) {
+		self.bpm = bpm;
+	}
+}
+
+impl<G: SampleRated> SampleRated for VowelFilter<G> {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.left.set_sample_rate(sample_rate);
+		self.right.set_sample_rate(sample_rate);
+		self.lfo_state.set_sample_rate(sample_rate);
+		self.inner.set_sample_rate(sample_rate);
+	}
+}
+
+impl<G: Generator> Generator for VowelFilter<G> {
+	fn generate(&mut self) -> [f32; 2] {
+		let [l, r] = self.inner.generate();
+		if !self.enabled {
+			return [l, r];
+		}
+		let lfo = self.lfo_state.run(&self.lfo, self.bpm);
+		let morph = self.morph + self.pressure * self.pressure_depth + lfo * self.lfo.routing.vowel;
+		self.left.set(self.voice, morph);
+		self.right.set(self.voice, morph);
+		[self.left.process(l as Sample) as f32, self.right.process(r as Sample) as f32]
+	}
+}
+
+impl<G: MidiDispatcher> MidiDispatcher for VowelFilter<G> {
+	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
+		use midistream::*;
+		if let Msg::Simple(SimpleMsg::ChannelKeyPressure(y)) = msg {
+			self.pressure = *y.value as Sample / 127.;
+		}
+		self.inner.dispatch_midi_in(msg);
+	}
+}
+
+/// Level a sine at `freq` comes through at, once settled.
+#[cfg(test)]
+fn response(formant: &mut Formant, freq: Frequency) -> Sample {
+	use std::f64::consts::PI;
+	formant.reset();
+	let mut peak: Sample = 0.;
+	for i in 0..9600 {
+		let y = formant.process((2. * PI * freq * i as f64 / 48000.).sin());
+		if i > 4800 {
+			peak = peak.max(y.abs());
+		}
+	}
+	peak
+}
+
+#[test]
+fn test_formant_vowels() {
+	let mut formant = Formant::new();
+	formant.set_sample_rate(48000);
+
+	// "a" has its first two formants far apart, "u" close and low
+	formant.set(VoiceType::Male, 0.);
+	assert!(response(&mut formant, 650.) > 0.9);
+	assert!(response(&mut formant, 1080.) > 0.5);
+	assert!(response(&mut formant, 1500.) < 0.2);
+	formant.set(VoiceType::Male, 4.);
+	assert!(response(&mut formant, 650.) < 0.3);
+	assert!(response(&mut formant, 350.) > 0.9);
+
+	// half way from "a" to "e" the first formant sits in between
+	formant.set(VoiceType::Male, 0.5);
+	assert!(response(&mut formant, 525.) > 0.9);
+
+	formant.set(VoiceType::Female, 0.);
+	assert!(response(&mut formant, 800.) > 0.9);
+	assert!(response(&mut formant, 650.) < 0.5);
+}
+
+#[test]
+fn test_vowel_filter() {
+	use super::oscillator::{Oscillator, Waveforms};
+	use midistream::*;
+	let mut vowels = VowelFilter::new(Oscillator::new(Waveforms::Saw));
+	vowels.set_sample_rate(48000);
+	vowels.pressure_depth = 4.;
+	vowels.dispatch_midi_in(&SimpleMsg::note_on(0, 48, 100).into());
+	vowels.dispatch_midi_in(&SimpleMsg::channel_key_pressure(0, 127).into());
+	assert_eq!(vowels.pressure, 1.);
+	let mut peak: f32 = 0.;
+	for _ in 0..4800 {
+		let [l, r] = vowels.generate();
+		assert_eq!(l, r);
+		peak = peak.max(l.abs());
+	}
+	assert!(peak > 0.);
+
+	// a square LFO flips between "i" and "a" every quarter second, moving
+	// the first formant from the saw's second harmonic to its fifth
+	use super::lfo::{Shape, Rate};
+	use super::distortion::level;
+	let mut vowels = VowelFilter::new(Oscillator::new(Waveforms::Saw));
+	vowels.set_sample_rate(48000);
+	vowels.morph = 1.;
+	vowels.lfo = Lfo { shape: Shape::Wave(Waveforms::Square), rate: Rate::Hz(2.), ..Lfo::new() };
+	vowels.lfo.routing.vowel = 1.;
+	vowels.dispatch_midi_in(&SimpleMsg::note_on(0, 48, 100).into());
+	let out: Vec<Sample> = (0..24000).map(|_| vowels.generate()[0] as Sample).collect();
+	let root = 440. * (2.0 as f64).powf((48. - 69.) / 12.);
+	let i = &out[4800..12000];
+	let a = &out[16800..24000];
+	assert!(level(i, 2. * root) > 2. * level(a, 2. * root));
+	assert!(level(a, 5. * root) > 2. * level(i, 5. * root));
+}
@@ -0,0 +1,218 @@
+use std::f64::consts::PI;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use super::types::{SampleRated, SampleRate, Sample, Frequency, Seconds};
+use super::oscillator::Waveforms;
+use super::modulation::Routing;
+use super::tempo::Division;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+	Wave(Waveforms),
+	/// A new random level at the start of every cycle.
+	SampleHold,
+	/// Glides between random levels, one per cycle.
+	SmoothRandom,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Rate {
+	Hz(Frequency),
+	Tempo(Division),
+}
+
+impl Rate {
+	pub fn frequency(&self, bpm: f64) -> Frequency {
+		match self {
+			Rate::Hz(freq) => *freq,
+			Rate::Tempo(division) => division.frequency(bpm),
+		}
+	}
+}
+
+/// LFO settings, part of the `Patch`. The output runs from -1 to 1 and is
+/// sent on through `routing`, with negative values inverting the depths.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lfo {
+	pub shape: Shape,
+	pub rate: Rate,
+	/// Restart at `phase` on every trigger rather than running freely.
+	pub key_sync: bool,
+	pub phase: f64,
+	/// Silent for `delay` after a trigger, then fades in over `fade_in`.
+	pub delay: Seconds,
+	pub fade_in: Seconds,
+	pub routing: Routing,
+}
+
+impl Lfo {
+	pub fn new() -> Lfo {
+		Lfo {
+			shape: Shape::Wave(Waveforms::Sine),
+			rate: Rate::Hz(5.),
+			key_sync: false,
+			phase: 0.,
+			delay: 0.,
+			fade_in: 0.,
+			routing: Routing::new(),
+		}
+	}
+	pub fn frequency(&self, bpm: f64) -> Frequency {
+		self.rate.frequency(bpm)
+	}
+	fn wave(waveform: Waveforms, phase: f64) -> Sample {
+		match waveform {
+			Waveforms::Sine     => (2. * PI * phase).sin(),
+			Waveforms::Square   => if phase < 0.5 { 1. } else { -1. },
+			Waveforms::Triangle => {
+				if phase < 0.25 { 4. * phase }
+				else if phase < 0.75 { 2. - 4. * phase }
+				else { 4. * phase - 4. }
+			},
+			Waveforms::Saw      => 2. * phase - 1.,
+			Waveforms::Noise    => random(),
+		}
+	}
+}
+
+fn random() -> Sample {
+	2. * rand::thread_rng().gen::<Sample>() - 1.
+}
+
+/// The running state of one LFO, either shared by all the voices of an
+/// `Oscillator` or one per voice.
+#[derive(Clone, Copy, Debug)]
+pub struct LfoState {
+	phase: f64,
+	elapsed: u64,
+	from: Sample,
+	to: Sample,
+	sample_rate: Frequency,
+}
+
+impl SampleRated for LfoState {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Frequency;
+	}
+}
+
+impl LfoState {
+	pub fn new() -> LfoState {
+		LfoState {
+			phase: 0.,
+			elapsed: 0,
+			from: 0.,
+			to: random(),
+			sample_rate: 0.,
+		}
+	}
+	/// Restarts the delay and fade in, and the phase if key synced.
+	pub fn trigger(&mut self, lfo: &Lfo) {
+		if lfo.key_sync {
+			self.phase = lfo.phase;
+		}
+		self.elapsed = 0;
+	}
+	pub fn run(&mut self, lfo: &Lfo, bpm: f64) -> Sample {
+		if self.sample_rate == 0. {
+			return 0.;
+		}
+		let value = match lfo.shape {
+			Shape::Wave(waveform) => Lfo::wave(waveform, self.phase),
+			Shape::SampleHold     => self.from,
+			Shape::SmoothRandom   => {
+				let t = 0.5 - 0.5 * (PI * self.phase).cos();
+				self.from + (self.to - self.from) * t
+			},
+		};
+		self.phase += lfo.frequency(bpm) / self.sample_rate;
+		if self.phase >= 1. {
+			self.phase -= self.phase.floor();
+			self.from = self.to;
+			self.to = random();
+		}
+
+		let time = self.elapsed as f64 / self.sample_rate - lfo.delay;
+		self.elapsed += 1;
+		if time < 0. {
+			0.
+		} else if time < lfo.fade_in {
+			value * time / lfo.fade_in
+		} else {
+			value
+		}
+	}
+}
+
+#[cfg(test)]
+fn run_for(state: &mut LfoState, lfo: &Lfo, samples: usize) -> Vec<Sample> {
+	(0..samples).map(|_| state.run(lfo, 120.)).collect()
+}
+
+#[test]
+fn test_lfo_shapes() {
+	let mut state = LfoState::new();
+	state.set_sample_rate(1000);
+	let mut lfo = Lfo { rate: Rate::Hz(4.), key_sync: true, ..Lfo::new() };
+	for (waveform, quarter) in &[
+		(Waveforms::Sine, 1.),
+		(Waveforms::Square, 1.),
+		(Waveforms::Triangle, 1.),
+		(Waveforms::Saw, -0.5),
+	] {
+		lfo.shape = Shape::Wave(*waveform);
+		state.trigger(&lfo);
+		let out = run_for(&mut state, &lfo, 250);
+		assert!((out[62] - quarter).abs() < 0.05, "{:?}: {}", waveform, out[62]);
+		assert!(out.iter().all(|v| v.abs() <= 1.));
+	}
+
+	// at 120 bpm an eighth note is a quarter second, 256 samples at 1024 Hz
+	state.set_sample_rate(1024);
+	lfo.shape = Shape::Wave(Waveforms::Saw);
+	lfo.rate = Rate::Tempo(Division::Eighth);
+	state.trigger(&lfo);
+	let out = run_for(&mut state, &lfo, 257);
+	assert_eq!(out[0], -1.);
+	assert!(out[255] > 0.99);
+	assert_eq!(out[256], -1.);
+
+	lfo.shape = Shape::SampleHold;
+	state.trigger(&lfo);
+	let out = run_for(&mut state, &lfo, 512);
+	assert!(out[1..256].iter().all(|&v| v == out[0]));
+	assert!(out[257..512].iter().all(|&v| v == out[256]));
+	assert!(out[0] != out[256]);
+
+	lfo.shape = Shape::SmoothRandom;
+	let out = run_for(&mut state, &lfo, 1000);
+	assert!(out.windows(2).all(|w| (w[1] - w[0]).abs() < 0.03));
+}
+
+#[test]
+fn test_lfo_fade_in() {
+	let mut state = LfoState::new();
+	state.set_sample_rate(1000);
+	let lfo = Lfo {
+		shape: Shape::Wave(Waveforms::Square),
+		rate: Rate::Hz(1.),
+		key_sync: true,
+		delay: 0.1,
+		fade_in: 0.2,
+		..Lfo::new()
+	};
+	state.trigger(&lfo);
+	let out = run_for(&mut state, &lfo, 400);
+	assert!(out[..100].iter().all(|&v| v == 0.));
+	assert!((out[200] - 0.5).abs() < 1e-9);
+	assert_eq!(out[350], 1.);
+
+	// free running keeps its phase across triggers, key synced restarts
+	let free = Lfo { key_sync: false, delay: 0., fade_in: 0., ..lfo };
+	state.trigger(&free);
+	assert_eq!(run_for(&mut state, &free, 200)[199], -1.);
+	let synced = Lfo { delay: 0., fade_in: 0., ..lfo };
+	state.trigger(&synced);
+	assert_eq!(state.run(&synced, 120.), 1.);
+}
@@ -8,3 +8,27 @@ pub mod midi;
 pub mod adsr;
 pub mod oscillator;
 pub mod temperament;
+pub mod smoother;
+pub mod patch;
+pub mod rpn;
+pub mod modulation;
+pub mod mpe;
+pub mod multitimbral;
+pub mod zones;
+pub mod tempo;
+pub mod arpeggiator;
+pub mod adaptive;
+pub mod envelope;
+pub mod filter;
+pub mod formant;
+pub mod lfo;
+pub mod matrix;
+pub mod macros;
+pub mod effects;
+pub mod delay;
+pub mod reverb;
+pub mod convolution;
+pub mod chorus;
+pub mod flanger;
+pub mod phaser;
+pub mod distortion;
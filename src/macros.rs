@@ -0,0 +1,136 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::Sample;
+use super::modulation::{Curve, Modulation};
+use super::matrix::Destination;
+
+pub const MACROS: usize = 8;
+pub const MAX_MAPPINGS: usize = 8;
+
+/// Where a `Mapping` sends its value.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Target {
+	/// Added to every voice's modulation.
+	Voice(Destination),
+	/// Sets parameter `param` of the effect in `slot` of the `Chain` the
+	/// oscillator plays through, numbered as in `Effect::params`.
+	Effect { slot: usize, param: usize },
+}
+
+/// One parameter moved by a macro: as the knob turns from 0 to 1 the
+/// target goes from `min` to `max` along `curve`. `max` may be below `min`
+/// to turn a parameter down.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+	pub target: Target,
+	pub min: f64,
+	pub max: f64,
+	pub curve: Curve,
+}
+
+impl Mapping {
+	pub fn new(destination: Destination, min: f64, max: f64) -> Mapping {
+		Mapping { target: Target::Voice(destination), min, max, curve: Curve::Linear }
+	}
+	/// An effect parameter, such as a reverb's size found with `Effect::find`.
+	pub fn effect(slot: usize, param: usize, min: f64, max: f64) -> Mapping {
+		Mapping { target: Target::Effect { slot, param }, min, max, curve: Curve::Linear }
+	}
+	fn value(&self, knob: Sample) -> f64 {
+		self.min + (self.max - self.min) * self.curve.apply(knob)
+	}
+}
+
+/// Generators whose patch carries macro knobs, for a `Chain` to set the
+/// effect parameters they are mapped to.
+pub trait Macros {
+	fn macros(&self) -> Option<&[Macro; MACROS]> {
+		None
+	}
+}
+
+/// A performance knob driving several parameters at once. Its position is
+/// saved with the patch.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Macro {
+	pub value: Sample,
+	pub cc: Option<u8>,
+	mappings: [Option<Mapping>; MAX_MAPPINGS],
+}
+
+impl Macro {
+	pub fn new() -> Macro {
+		Macro {
+			value: 0.,
+			cc: None,
+			mappings: [None; MAX_MAPPINGS],
+		}
+	}
+	/// Adds a mapping, returning false when they are all taken.
+	pub fn map(&mut self, mapping: Mapping) -> bool {
+		match self.mappings.iter_mut().find(|m| m.is_none()) {
+			Some(free) => {
+				*free = Some(mapping);
+				true
+			},
+			None => false,
+		}
+	}
+	pub fn clear(&mut self) {
+		self.mappings = [None; MAX_MAPPINGS];
+	}
+	pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+		self.mappings.iter().flatten()
+	}
+	/// Adds the voice mappings to `mods`.
+	pub fn apply(&self, mods: &mut Modulation) {
+		for mapping in self.mappings() {
+			if let Target::Voice(destination) = mapping.target {
+				destination.add(mapping.value(self.value), mods);
+			}
+		}
+	}
+	/// The slot, parameter and value of each effect mapping.
+	pub fn effect_values(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+		self.mappings().filter_map(move |mapping| match mapping.target {
+			Target::Effect { slot, param } => Some((slot, param, mapping.value(self.value))),
+			Target::Voice(_) => None,
+		})
+	}
+}
+
+#[test]
+fn test_macro() {
+	let mut brightness = Macro::new();
+	assert!(brightness.map(Mapping::new(Destination::Cutoff, 0., 24.)));
+	assert!(brightness.map(Mapping::new(Destination::Resonance, 0.5, 0.)));
+	assert!(brightness.map(Mapping { curve: Curve::Exponential, ..Mapping::new(Destination::WavePosition, 0., 1.) }));
+
+	let mut mods = Modulation::new();
+	brightness.apply(&mut mods);
+	assert_eq!(mods.cutoff, 0.);
+	assert_eq!(mods.resonance, 0.5);
+
+	brightness.value = 0.5;
+	let mut mods = Modulation::new();
+	brightness.apply(&mut mods);
+	assert_eq!(mods.cutoff, 12.);
+	assert_eq!(mods.resonance, 0.25);
+	assert_eq!(mods.wave_position, 0.25);
+	assert_eq!(brightness.mappings().count(), 3);
+
+	for _ in 3..MAX_MAPPINGS {
+		assert!(brightness.map(Mapping::new(Destination::Pan, 0., 0.)));
+	}
+	assert!(!brightness.map(Mapping::new(Destination::Pan, 0., 0.)));
+	brightness.clear();
+	assert_eq!(brightness.mappings().count(), 0);
+
+	// effect mappings leave the voices alone
+	brightness.map(Mapping::new(Destination::Cutoff, 0., 24.));
+	assert!(brightness.map(Mapping::effect(1, 2, 0.2, 0.6)));
+	let mut mods = Modulation::new();
+	brightness.apply(&mut mods);
+	assert_eq!(mods.cutoff, 12.);
+	assert_eq!(brightness.effect_values().collect::<Vec<_>>(), vec![(1, 2, 0.4)]);
+}
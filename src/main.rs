@@ -41,7 +41,8 @@ fn main() {
 	let interrupt = std::sync::Arc::new( std::sync::atomic::AtomicBool::new( false ) );
 	signal_hook::flag::register(signal_hook::SIGINT, std::sync::Arc::clone(&interrupt)).unwrap();
 
-	let osc = Box::new(crate::oscillator::Oscillator::new(crate::oscillator::Waveforms::Sine));
+	let osc = crate::oscillator::Oscillator::new(crate::oscillator::Waveforms::Sine);
+	let osc = Box::new(crate::effects::Chain::new(osc));
 	let mut midi = crate::midi::InputThread::new();
 	let sys = crate::audio::System::new();
 	println!("Sample format: {:?}", sys.sample_format());
@@ -0,0 +1,221 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::Sample;
+use super::modulation::Modulation;
+
+pub const SLOTS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Source {
+	Off,
+	Lfo,
+	VoiceLfo,
+	AmpEnv,
+	FltEnv,
+	Velocity,
+	Key,
+	PolyPressure,
+	ChannelPressure,
+	ModWheel,
+	PitchBend,
+	Cc(u8),
+	/// A fresh random value for every note.
+	Random,
+}
+
+impl Source {
+	/// Whether the source naturally swings either side of zero.
+	pub fn is_bipolar(&self) -> bool {
+		match self {
+			Source::Lfo | Source::VoiceLfo | Source::PitchBend => true,
+			_ => false,
+		}
+	}
+}
+
+/// Where a slot sends its source. Amounts are in semitones for `Pitch` and
+/// `Cutoff`; the rest add to a value running from 0 to 1, or -1 to 1 for
+/// `Pan`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Destination {
+	Pitch,
+	Amplitude,
+	Pan,
+	Cutoff,
+	Resonance,
+	PulseWidth,
+	WavePosition,
+}
+
+impl Destination {
+	pub fn add(&self, v: f64, mods: &mut Modulation) {
+		match self {
+			Destination::Pitch        => mods.pitch         += v,
+			Destination::Amplitude    => mods.amplitude     += v,
+			Destination::Pan          => mods.pan           += v,
+			Destination::Cutoff       => mods.cutoff        += v,
+			Destination::Resonance    => mods.resonance     += v,
+			Destination::PulseWidth   => mods.pulse_width   += v,
+			Destination::WavePosition => mods.wave_position += v,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Slot {
+	pub source: Source,
+	/// Scales the slot by a second source, e.g. the mod wheel bringing in
+	/// an LFO; `Off` leaves it unscaled.
+	pub via: Source,
+	pub destination: Destination,
+	pub amount: f64,
+	/// Run the source from -1 to 1 rather than 0 to 1, converting it if it
+	/// is naturally the other way.
+	pub bipolar: bool,
+}
+
+impl Slot {
+	pub fn new(source: Source, destination: Destination, amount: f64) -> Slot {
+		Slot {
+			source,
+			via: Source::Off,
+			destination,
+			amount,
+			bipolar: source.is_bipolar(),
+		}
+	}
+}
+
+/// The value of every source for one voice, at one sample. All run from 0
+/// to 1, apart from the bipolar ones.
+pub struct Sources<'a> {
+	pub lfo: Sample,
+	pub voice_lfo: Sample,
+	pub amp_env: Sample,
+	pub flt_env: Sample,
+	pub velocity: Sample,
+	pub key: Sample,
+	pub poly_pressure: Sample,
+	pub channel_pressure: Sample,
+	pub pitch_bend: Sample,
+	pub random: Sample,
+	pub controllers: &'a [Sample; 128],
+}
+
+impl<'a> Sources<'a> {
+	pub fn value(&self, source: Source) -> Sample {
+		match source {
+			Source::Off             => 0.,
+			Source::Lfo             => self.lfo,
+			Source::VoiceLfo        => self.voice_lfo,
+			Source::AmpEnv          => self.amp_env,
+			Source::FltEnv          => self.flt_env,
+			Source::Velocity        => self.velocity,
+			Source::Key             => self.key,
+			Source::PolyPressure    => self.poly_pressure,
+			Source::ChannelPressure => self.channel_pressure,
+			Source::ModWheel        => self.controllers[1],
+			Source::PitchBend       => self.pitch_bend,
+			Source::Cc(cc)          => self.controllers[(cc & 0x7f) as usize],
+			Source::Random          => self.random,
+		}
+	}
+	fn unipolar(&self, source: Source) -> Sample {
+		let v = self.value(source);
+		if source.is_bipolar() { (v + 1.) / 2. } else { v }
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Matrix {
+	pub slots: [Slot; SLOTS],
+}
+
+impl Matrix {
+	pub fn new() -> Matrix {
+		Matrix {
+			slots: [Slot::new(Source::Off, Destination::Amplitude, 0.); SLOTS],
+		}
+	}
+	/// Puts `slot` in the first free one, returning its index.
+	pub fn add(&mut self, slot: Slot) -> Option<usize> {
+		let i = self.slots.iter().position(|slot| slot.source == Source::Off)?;
+		self.slots[i] = slot;
+		Some(i)
+	}
+	pub fn apply(&self, sources: &Sources, mods: &mut Modulation) {
+		for slot in self.slots.iter() {
+			if slot.source == Source::Off || slot.amount == 0. {
+				continue;
+			}
+			let mut v = match (slot.source.is_bipolar(), slot.bipolar) {
+				(false, true) => 2. * sources.value(slot.source) - 1.,
+				(false, false) | (true, true) => sources.value(slot.source),
+				(true, false) => sources.unipolar(slot.source),
+			};
+			if slot.via != Source::Off {
+				v *= sources.unipolar(slot.via);
+			}
+			slot.destination.add(v * slot.amount, mods);
+		}
+	}
+}
+
+#[cfg(test)]
+fn sources(controllers: &[Sample; 128]) -> Sources<'_> {
+	Sources {
+		lfo: -0.5,
+		voice_lfo: 0.,
+		amp_env: 0.5,
+		flt_env: 0.25,
+		velocity: 1.,
+		key: 0.5,
+		poly_pressure: 0.,
+		channel_pressure: 0.,
+		pitch_bend: 0.,
+		random: 0.75,
+		controllers,
+	}
+}
+
+#[test]
+fn test_matrix_polarity() {
+	let mut controllers = [0.; 128];
+	controllers[1] = 0.5;
+	let sources = sources(&controllers);
+	let mut matrix = Matrix::new();
+	matrix.add(Slot::new(Source::Lfo, Destination::Pitch, 2.));
+	matrix.add(Slot { bipolar: false, ..Slot::new(Source::Lfo, Destination::Cutoff, 12.) });
+	matrix.add(Slot { bipolar: true, ..Slot::new(Source::FltEnv, Destination::Pan, 1.) });
+	matrix.add(Slot::new(Source::Random, Destination::WavePosition, 1.));
+	let mut mods = Modulation::new();
+	matrix.apply(&sources, &mut mods);
+	assert_eq!(mods.pitch, -1.);
+	assert_eq!(mods.cutoff, 3.);
+	assert_eq!(mods.pan, -0.5);
+	assert_eq!(mods.wave_position, 0.75);
+	assert_eq!(mods.amplitude, 0.);
+}
+
+#[test]
+fn test_matrix_via() {
+	let mut controllers = [0.; 128];
+	controllers[1] = 0.5;
+	controllers[74] = 1.;
+	let sources = sources(&controllers);
+	let mut matrix = Matrix::new();
+	// vibrato brought in by the mod wheel
+	matrix.add(Slot { via: Source::ModWheel, ..Slot::new(Source::Lfo, Destination::Pitch, 1.) });
+	matrix.add(Slot { via: Source::Velocity, ..Slot::new(Source::Cc(74), Destination::Resonance, 0.5) });
+	matrix.add(Slot { via: Source::Lfo, ..Slot::new(Source::AmpEnv, Destination::PulseWidth, 1.) });
+	let mut mods = Modulation::new();
+	matrix.apply(&sources, &mut mods);
+	assert_eq!(mods.pitch, -0.25);
+	assert_eq!(mods.resonance, 0.5);
+	assert_eq!(mods.pulse_width, 0.125);
+
+	for i in 3..SLOTS {
+		assert_eq!(matrix.add(Slot::new(Source::Key, Destination::Amplitude, 0.1)), Some(i));
+	}
+	assert_eq!(matrix.add(Slot::new(Source::Key, Destination::Amplitude, 0.1)), None);
+}
@@ -0,0 +1,211 @@
+use std::ops::AddAssign;
+use serde::{Serialize, Deserialize};
+
+use super::types::{Sample, Semitones};
+
+pub const MAX_BREAKPOINTS: usize = 8;
+
+/// A user-drawn curve through up to `MAX_BREAKPOINTS` points, joined by
+/// straight lines. Points must be added in increasing `x`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Breakpoints {
+	points: [(Sample, Sample); MAX_BREAKPOINTS],
+	len: usize,
+}
+
+impl Breakpoints {
+	pub fn new(points: &[(Sample, Sample)]) -> Breakpoints {
+		let mut bp = Breakpoints {
+			points: [(0., 0.); MAX_BREAKPOINTS],
+			len: points.len().min(MAX_BREAKPOINTS),
+		};
+		bp.points[..bp.len].copy_from_slice(&points[..bp.len]);
+		bp
+	}
+	pub fn apply(&self, x: Sample) -> Sample {
+		let points = &self.points[..self.len];
+		match points.iter().position(|&(px, _)| px >= x) {
+			None if self.len == 0 => x,
+			None => points[self.len - 1].1,
+			Some(0) => points[0].1,
+			Some(i) => {
+				let (x0, y0) = points[i - 1];
+				let (x1, y1) = points[i];
+				y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+			},
+		}
+	}
+}
+
+/// Response curve applied to a unipolar controller value in `0..=1`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+	Linear,
+	Exponential,
+	Logarithmic,
+	SCurve,
+	/// Ignores the input, e.g. for a fixed velocity.
+	Fixed(Sample),
+	Breakpoints(Breakpoints),
+}
+
+impl Curve {
+	pub fn apply(&self, x: Sample) -> Sample {
+		let x = x.max(0.).min(1.);
+		match self {
+			Curve::Linear      => x,
+			Curve::Exponential => x * x,
+			Curve::Logarithmic => 1. - (1. - x) * (1. - x),
+			Curve::SCurve      => x * x * (3. - 2. * x),
+			Curve::Fixed(y)    => *y,
+			Curve::Breakpoints(bp) => bp.apply(x),
+		}
+	}
+}
+
+/// Offsets gathered for one voice from every modulation source, then
+/// applied by the voice when it renders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modulation {
+	pub amplitude: Sample,
+	pub cutoff: Semitones,
+	pub vibrato: Semitones,
+	pub wave_position: Sample,
+	/// Envelope time scaling, in octaves: +1 doubles the stage times.
+	pub env_time: f64,
+	/// Movement through the vowels of a formant filter.
+	pub vowel: f64,
+	pub pitch: Semitones,
+	/// Stereo position, -1 to 1.
+	pub pan: Sample,
+	pub resonance: Sample,
+	pub pulse_width: Sample,
+}
+
+impl Modulation {
+	pub fn new() -> Modulation {
+		Modulation {
+			amplitude: 0.,
+			cutoff: 0.,
+			vibrato: 0.,
+			wave_position: 0.,
+			env_time: 0.,
+			vowel: 0.,
+			pitch: 0.,
+			pan: 0.,
+			resonance: 0.,
+			pulse_width: 0.,
+		}
+	}
+	pub fn gain(&self) -> Sample {
+		(1. + self.amplitude).max(0.)
+	}
+	pub fn time_scale(&self) -> f64 {
+		(2.0 as f64).powf(self.env_time)
+	}
+}
+
+impl AddAssign for Modulation {
+	fn add_assign(&mut self, other: Modulation) {
+		self.amplitude     += other.amplitude;
+		self.cutoff        += other.cutoff;
+		self.vibrato       += other.vibrato;
+		self.wave_position += other.wave_position;
+		self.env_time      += other.env_time;
+		self.vowel         += other.vowel;
+		self.pitch         += other.pitch;
+		self.pan           += other.pan;
+		self.resonance     += other.resonance;
+		self.pulse_width   += other.pulse_width;
+	}
+}
+
+/// Sends a controller such as aftertouch to the patch destinations, each
+/// with its own depth, after shaping it through `curve`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Routing {
+	pub curve: Curve,
+	pub amplitude: Sample,
+	pub cutoff: Semitones,
+	pub vibrato: Semitones,
+	/// Also serves as brightness, as it sweeps towards the brighter
+	/// `wave_morph` waveform.
+	pub wave_position: Sample,
+	pub env_time: f64,
+	pub vowel: f64,
+}
+
+impl Routing {
+	pub fn new() -> Routing {
+		Routing {
+			curve: Curve::Linear,
+			amplitude: 0.,
+			cutoff: 0.,
+			vibrato: 0.,
+			wave_position: 0.,
+			env_time: 0.,
+			vowel: 0.,
+		}
+	}
+	pub fn apply(&self, value: Sample, mods: &mut Modulation) {
+		if value == 0. {
+			return;
+		}
+		self.add(self.curve.apply(value), mods);
+	}
+	/// For sources that swing either side of zero, such as LFOs: the curve
+	/// shapes the size and the sign is kept.
+	pub fn apply_bipolar(&self, value: Sample, mods: &mut Modulation) {
+		if value == 0. {
+			return;
+		}
+		self.add(value.signum() * self.curve.apply(value.abs()), mods);
+	}
+	fn add(&self, v: Sample, mods: &mut Modulation) {
+		mods.amplitude     += v * self.amplitude;
+		mods.cutoff        += v * self.cutoff;
+		mods.vibrato       += v * self.vibrato;
+		mods.wave_position += v * self.wave_position;
+		mods.env_time      += v * self.env_time;
+		mods.vowel         += v * self.vowel;
+	}
+}
+
+#[test]
+fn test_curves() {
+	for curve in &[Curve::Linear, Curve::Exponential, Curve::Logarithmic, Curve::SCurve] {
+		assert_eq!(curve.apply(0.), 0.);
+		assert_eq!(curve.apply(1.), 1.);
+		assert_eq!(curve.apply(2.), 1.);
+	}
+	assert!(Curve::Exponential.apply(0.5) < 0.5);
+	assert!(Curve::Logarithmic.apply(0.5) > 0.5);
+	assert_eq!(Curve::SCurve.apply(0.5), 0.5);
+	assert!(Curve::SCurve.apply(0.25) < 0.25);
+	assert_eq!(Curve::Fixed(0.8).apply(0.1), 0.8);
+}
+
+#[test]
+fn test_breakpoints() {
+	let curve = Curve::Breakpoints(Breakpoints::new(&[(0.2, 0.), (0.6, 0.8), (1., 1.)]));
+	assert_eq!(curve.apply(0.), 0.);
+	assert_eq!(curve.apply(0.2), 0.);
+	assert!((curve.apply(0.4) - 0.4).abs() < 1e-12);
+	assert!((curve.apply(0.8) - 0.9).abs() < 1e-12);
+	assert_eq!(curve.apply(1.), 1.);
+	assert_eq!(Breakpoints::new(&[]).apply(0.3), 0.3);
+}
+
+#[test]
+fn test_routing() {
+	let mut routing = Routing::new();
+	routing.curve = Curve::Exponential;
+	routing.amplitude = -1.;
+	routing.cutoff = 24.;
+	let mut mods = Modulation::new();
+	routing.apply(0.5, &mut mods);
+	assert_eq!(mods.amplitude, -0.25);
+	assert_eq!(mods.cutoff, 6.);
+	assert_eq!(mods.vibrato, 0.);
+	assert_eq!(mods.gain(), 0.75);
+}
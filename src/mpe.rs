@@ -0,0 +1,155 @@
+use super::types::{Sample, Semitones};
+
+pub const RPN_MPE_CONFIGURATION: u16 = 6;
+pub const CC_SLIDE: u8 = 74;
+
+const LOWER_MANAGER: u8 = 0;
+const UPPER_MANAGER: u8 = 15;
+const MEMBER_BEND_RANGE: Semitones = 48.;
+
+/// The last expression values received on a channel. MPE controllers send
+/// these just before the note on, so new voices pick them up from here.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expression {
+	pub bend: f64,
+	pub pressure: Sample,
+	pub timbre: Sample,
+}
+
+impl Expression {
+	pub fn new() -> Expression {
+		Expression {
+			bend: 0.,
+			pressure: 0.,
+			timbre: 0.,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+	Manager,
+	Member,
+}
+
+/// One MPE zone. A zone with no member channels is disabled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Zone {
+	pub members: u8,
+	pub bend_range: Semitones,
+}
+
+impl Zone {
+	pub fn new() -> Zone {
+		Zone {
+			members: 0,
+			bend_range: MEMBER_BEND_RANGE,
+		}
+	}
+	pub fn is_enabled(&self) -> bool {
+		self.members > 0
+	}
+}
+
+/// Lower and upper zone layout, as set by the MPE Configuration Message.
+/// The lower zone is managed from channel 1 and grows upwards; the upper
+/// zone is managed from channel 16 and grows downwards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mpe {
+	pub lower: Zone,
+	pub upper: Zone,
+}
+
+impl Mpe {
+	pub fn new() -> Mpe {
+		Mpe {
+			lower: Zone::new(),
+			upper: Zone::new(),
+		}
+	}
+	pub fn is_enabled(&self) -> bool {
+		self.lower.is_enabled() || self.upper.is_enabled()
+	}
+	/// Applies an MPE Configuration Message received on `channel`.
+	/// Returns false if the channel cannot manage a zone.
+	pub fn configure(&mut self, channel: u8, members: u8) -> bool {
+		let members = members.min(15);
+		match channel {
+			LOWER_MANAGER => {
+				self.lower = Zone { members: members, ..Zone::new() };
+				if self.upper.members + members > 14 {
+					self.upper.members = 14_u8.saturating_sub(members);
+				}
+			},
+			UPPER_MANAGER => {
+				self.upper = Zone { members: members, ..Zone::new() };
+				if self.lower.members + members > 14 {
+					self.lower.members = 14_u8.saturating_sub(members);
+				}
+			},
+			_ => return false,
+		}
+		true
+	}
+	pub fn zone(&self, channel: u8) -> Option<(&Zone, Role)> {
+		let lower = &self.lower;
+		let upper = &self.upper;
+		if lower.is_enabled() {
+			if channel == LOWER_MANAGER {
+				return Some((lower, Role::Manager));
+			}
+			if channel <= lower.members {
+				return Some((lower, Role::Member));
+			}
+		}
+		if upper.is_enabled() {
+			if channel == UPPER_MANAGER {
+				return Some((upper, Role::Manager));
+			}
+			if channel >= UPPER_MANAGER - upper.members {
+				return Some((upper, Role::Member));
+			}
+		}
+		None
+	}
+	pub fn zone_mut(&mut self, channel: u8) -> Option<&mut Zone> {
+		let lower = match self.zone(channel) {
+			Some((zone, _)) => std::ptr::eq(zone, &self.lower),
+			None => return None,
+		};
+		if lower { Some(&mut self.lower) } else { Some(&mut self.upper) }
+	}
+	pub fn is_member(&self, channel: u8) -> bool {
+		match self.zone(channel) {
+			Some((_, Role::Member)) => true,
+			_ => false,
+		}
+	}
+}
+
+#[test]
+fn test_mpe_zones() {
+	let mut mpe = Mpe::new();
+	assert!(!mpe.is_enabled());
+	assert_eq!(mpe.zone(1), None);
+
+	assert!(mpe.configure(0, 7));
+	assert!(mpe.is_enabled());
+	assert_eq!(mpe.zone(0), Some((&mpe.lower, Role::Manager)));
+	assert_eq!(mpe.zone(7), Some((&mpe.lower, Role::Member)));
+	assert_eq!(mpe.zone(8), None);
+	assert_eq!(mpe.lower.bend_range, 48.);
+
+	assert!(mpe.configure(15, 9));
+	assert_eq!(mpe.lower.members, 5);
+	assert_eq!(mpe.zone(6), Some((&mpe.upper, Role::Member)));
+	assert_eq!(mpe.zone(15), Some((&mpe.upper, Role::Manager)));
+	mpe.zone_mut(6).unwrap().bend_range = 24.;
+	assert_eq!(mpe.upper.bend_range, 24.);
+	assert_eq!(mpe.lower.bend_range, 48.);
+
+	assert!(!mpe.configure(3, 4));
+	assert!(mpe.configure(0, 0));
+	assert!(mpe.configure(15, 0));
+	assert!(!mpe.is_enabled());
+}
@@ -0,0 +1,241 @@
+use super::types::{SampleRated, Generator, SampleRate, Sample, MidiDispatcher, Tempo};
+use super::oscillator::{Oscillator, Waveforms};
+use super::effects::{Effects, pan_gains};
+use super::macros::Macros;
+
+pub const MAX_PARTS: usize = 16;
+
+/// One instrument in a multitimbral setup, listening on a single MIDI
+/// channel. The oscillator carries the patch, waveform and tuning, and the
+/// part's own effects come before its volume and pan.
+pub struct Part {
+	pub osc: Oscillator,
+	pub effects: Effects,
+	pub volume: Sample,
+	pub pan: Sample,
+	pub mute: bool,
+	pub solo: bool,
+}
+
+impl Part {
+	pub fn new(waveform: Waveforms) -> Part {
+		Part {
+			osc: Oscillator::new(waveform),
+			effects: Effects::new(),
+			volume: 1.,
+			pan: 0.,
+			mute: false,
+			solo: false,
+		}
+	}
+	/// Volume and pan as left and right gains, with `pan` running from -1
+	/// (left) to 1 (right).
+	fn gains(&self) -> (Sample, Sample) {
+		let [l, r] = pan_gains(self.pan);
+		(l * self.volume, r * self.volume)
+	}
+}
+
+/// Up to 16 parts, addressed by MIDI channel, mixed into one output.
+pub struct Multitimbral {
+	parts: Vec<Option<Part>>,
+	sample_rate: SampleRate,
+	bpm: f64,
+}
+
+impl SampleRated for Multitimbral {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate;
+		for part in self.parts.iter_mut().flatten() {
+			part.osc.set_sample_rate(sample_rate);
+			part.effects.set_sample_rate(sample_rate);
+		}
+	}
+}
+
+impl Multitimbral {
+	pub fn new() -> Multitimbral {
+		let mut parts = Vec::with_capacity(MAX_PARTS);
+		parts.resize_with(MAX_PARTS, || None);
+		Multitimbral {
+			parts: parts,
+			sample_rate: 0,
+			bpm: 120.,
+		}
+	}
+	pub fn set_part(&mut self, channel: usize, mut part: Part) {
+		if self.sample_rate > 0 {
+			part.osc.set_sample_rate(self.sample_rate);
+			part.effects.set_sample_rate(self.sample_rate);
+		}
+		part.osc.set_bpm(self.bpm);
+		part.effects.set_bpm(self.bpm);
+		self.parts[channel] = Some(part);
+	}
+	pub fn remove_part(&mut self, channel: usize) -> Option<Part> {
+		self.parts[channel].take()
+	}
+	pub fn part(&self, channel: usize) -> Option<&Part> {
+		self.parts[channel].as_ref()
+	}
+	pub fn part_mut(&mut self, channel: usize) -> Option<&mut Part> {
+		self.parts[channel].as_mut()
+	}
+	/// Tempo for every part's synced LFOs and effects.
+	pub fn set_bpm(&mut self, bpm: f64) {
+		self.bpm = bpm;
+		for part in self.parts.iter_mut().flatten() {
+			part.osc.set_bpm(bpm);
+			part.effects.set_bpm(bpm);
+		}
+	}
+	fn channel(msg: &midistream::Msg) -> Option<u8> {
+		use midistream::*;
+		match msg {
+			Msg::Simple(x) => match x {
+				SimpleMsg::NoteOff(y) |
+				SimpleMsg::NoteOn(y) |
+				SimpleMsg::PolyKeyPressure(y) => Some(*y.channel),
+				SimpleMsg::ControlChange(y) => Some(*y.channel),
+				SimpleMsg::ProgramChange(y) |
+				SimpleMsg::ChannelKeyPressure(y) => Some(*y.channel),
+				SimpleMsg::PitchBendChange(y) => Some(*y.channel),
+				_ => None,
+			},
+			Msg::Complex(x) => match x {
+				ComplexMsg::ControlChange14(y) => Some(*y.channel),
+				ComplexMsg::RPNChange(y) |
+				ComplexMsg::NRPNChange(y) => Some(*y.channel),
+			},
+			Msg::Sysex(_) => None,
+		}
+	}
+}
+
+impl Generator for Multitimbral {
+	fn generate(&mut self) -> [f32; 2] {
+		let soloing = self.parts.iter().flatten().any(|part| part.solo);
+		let mut left: Sample = 0.;
+		let mut right: Sample = 0.;
+		for part in self.parts.iter_mut().flatten() {
+			let [l, r] = part.osc.generate();
+			let out = part.effects.process([l as Sample, r as Sample]);
+			if part.mute || (soloing && !part.solo) {
+				continue;
+			}
+			let (gain_l, gain_r) = part.gains();
+			left  += out[0] * gain_l;
+			right += out[1] * gain_r;
+		}
+		[left as f32, right as f32]
+	}
+}
+
+impl Tempo for Multitimbral {
+	fn set_bpm(&mut self, bpm: f64) {
+		Multitimbral::set_bpm(self, bpm);
+	}
+}
+
+/// Each part's macros only reach its own voices.
+impl Macros for Multitimbral {}
+
+impl MidiDispatcher for Multitimbral {
+	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
+		match Self::channel(msg) {
+			Some(channel) => {
+				if let Some(part) = self.parts[channel as usize].as_mut() {
+					part.osc.dispatch_midi_in(msg);
+				}
+			},
+			None => {
+				for part in self.parts.iter_mut().flatten() {
+					part.osc.dispatch_midi_in(msg);
+				}
+			},
+		}
+	}
+}
+
+#[test]
+fn test_multitimbral() {
+	use midistream::SimpleMsg;
+	let mut multi = Multitimbral::new();
+	multi.set_part(0, Part::new(Waveforms::Sine));
+	multi.set_sample_rate(48000);
+	multi.set_part(1, Part::new(Waveforms::Square));
+	multi.part_mut(1).unwrap().pan = 1.;
+	// the same law as the voices and `Pan`, unity in the centre
+	let (l, r) = multi.part(0).unwrap().gains();
+	assert!((l - 1.).abs() < 1e-12 && (r - 1.).abs() < 1e-12);
+
+	multi.dispatch_midi_in(&SimpleMsg::note_on(1, 60, 100).into());
+	multi.dispatch_midi_in(&SimpleMsg::note_on(5, 60, 100).into());
+	assert_eq!(multi.part(0).unwrap().osc.active_voices(), 0);
+	assert_eq!(multi.part(1).unwrap().osc.active_voices(), 1);
+
+	let mut peak = [0_f32; 2];
+	for _ in 0..4800 {
+		let out = multi.generate();
+		peak[0] = peak[0].max(out[0].abs());
+		peak[1] = peak[1].max(out[1].abs());
+	}
+	assert!(peak[0] < 1e-6, "hard right pan leaks {} into left", peak[0]);
+	assert!(peak[1] > 0.1);
+
+	multi.dispatch_midi_in(&SimpleMsg::note_on(0, 64, 100).into());
+	multi.part_mut(1).unwrap().mute = true;
+	multi.generate();
+	let out = multi.generate();
+	assert_eq!(out[1], out[0], "only the centered part should be heard");
+
+	multi.part_mut(1).unwrap().mute = false;
+	multi.part_mut(1).unwrap().solo = true;
+	for _ in 0..10 {
+		let out = multi.generate();
+		assert!(out[0].abs() < 1e-6);
+	}
+}
+
+#[test]
+fn test_part_effects() {
+	use midistream::SimpleMsg;
+	use super::effects::Pan;
+	let mut multi = Multitimbral::new();
+	multi.set_sample_rate(48000);
+	multi.set_part(0, Part::new(Waveforms::Sine));
+	multi.set_part(1, Part::new(Waveforms::Sine));
+	// a part's effects only touch that part
+	let part = multi.part_mut(1).unwrap();
+	let pan = part.effects.push(Box::new(Pan::new()));
+	part.effects.effect_mut(pan).set(0, 0.);
+	multi.dispatch_midi_in(&SimpleMsg::note_on(1, 60, 100).into());
+	for _ in 0..4800 {
+		assert_eq!(multi.generate(), [0.; 2]);
+	}
+	multi.dispatch_midi_in(&SimpleMsg::note_on(0, 60, 100).into());
+	let peak = (0..4800).fold(0_f32, |peak, _| peak.max(multi.generate()[0].abs()));
+	assert!(peak > 0.1);
+}
+
+#[test]
+fn test_part_tempo() {
+	use midistream::SimpleMsg;
+	use super::effects::{Chain, Metronome};
+	let mut chain = Chain::new(Multitimbral::new());
+	chain.set_sample_rate(1000);
+	let mut part = Part::new(Waveforms::Sine);
+	let metronome = part.effects.push(Box::new(Metronome(0.)));
+	chain.inner.set_part(3, part);
+	assert_eq!(chain.inner.part(3).unwrap().effects.effect(metronome).get(0), 120.);
+
+	// a tick every 25 samples is 100 bpm, for the part's effects and LFOs alike
+	chain.dispatch_midi_in(&SimpleMsg::Start.into());
+	for _ in 0..48 {
+		chain.dispatch_midi_in(&SimpleMsg::TimingClock.into());
+		for _ in 0..25 { chain.generate(); }
+	}
+	let part = chain.inner.part(3).unwrap();
+	assert!((part.effects.effect(metronome).get(0) - 100.).abs() < 1e-9);
+	assert!((part.osc.bpm() - 100.).abs() < 1e-9);
+}
@@ -1,17 +1,34 @@
 use std::collections::VecDeque;
 use rand::Rng;
+use serde::{Serialize, Deserialize};
 use std::num::Wrapping;
 use std::f64::consts::PI;
 use std::convert::TryInto;
 
-use super::types::{SampleRated, Generator, SampleRate, Frequency, Sample, MidiDispatcher};
+use super::types::{SampleRated, Generator, SampleRate, Frequency, Sample, Semitones, Cents, MidiDispatcher, Tempo};
 use super::adsr::*;
+use super::envelope::ContourState;
 use super::temperament::{Tuning,TuningData};
+use super::smoother::Smoother;
+use super::patch::Patch;
+use super::modulation::Modulation;
+use super::mpe::*;
+use super::adaptive::AdaptiveTuning;
+use super::filter::{Kind, Svf, Ladder};
+use super::formant::Formant;
+use super::distortion::Shaper;
+use super::lfo::LfoState;
+use super::matrix::Sources;
+use super::rpn::*;
+use super::effects::pan_gains;
+use super::macros::{Macro, Macros, MACROS};
 
 const TABLE_BITS: usize = 19;
 const TABLE_SIZE: usize = 1 << TABLE_BITS;
 const SHIFT: u32 = 32 - TABLE_BITS as u32;
 const RESOLUTION: f64 = (1_i64 << 32) as f64;
+const BEND_CENTER: f64 = 8192.;
+const BEND_SMOOTHING: f64 = 0.005;
 
 type TablePos = u32;
 
@@ -80,7 +97,7 @@ impl Counter {
 	}
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Waveforms {
 	Sine,
 	Square,
@@ -104,6 +121,25 @@ impl WaveTable {
 		ret.setup_table(waveform);
 		ret
 	}
+	fn peek(&self, phase: &Counter) -> Sample {
+		self.table[phase.int() as usize]
+	}
+	/// Crossfades from `self` to `other` as `pos` goes from 0 to 1.
+	fn morph(&self, other: &WaveTable, pos: Sample, phase: &mut Counter) -> Sample {
+		if pos <= 0. {
+			return self.lookup(phase);
+		}
+		let y = self.peek(phase) * (1. - pos) + other.peek(phase) * pos;
+		phase.increment();
+		y
+	}
+	/// A pulse of duty cycle `width`, from the difference of two copies of
+	/// this table, which should be the sawtooth, offset in phase.
+	fn pulse(&self, width: Sample, phase: &Counter) -> Sample {
+		let offset = Wrapping((width * RESOLUTION) as TablePos);
+		let shifted = ((phase.phase + offset).0 >> phase.bits.0) as usize;
+		self.peek(phase) - self.table[shifted] + 2. * width - 1.
+	}
 	fn lookup(&self, phase: &mut Counter) -> Sample {
 		let p0 = phase.int() as usize; phase.increment();
 		//let p1 = phase.int() as usize;
@@ -174,24 +210,55 @@ impl WaveTable {
 #[derive(Clone, Debug)]
 struct Note {
 	phase: Counter,
+	freq: Frequency,
 	amp: Sample,
 	flt: Sample,
-	amp_env: ADSR,
-	flt_env: ADSR,
+	amp_env: ContourState,
+	flt_env: ContourState,
+	shaper: Shaper,
+	svf: Svf,
+	ladder: Ladder,
+	formant: Formant,
+	lfo: LfoState,
+	random: Sample,
 	down: bool,
 	vel: f64,
+	vel_mods: Modulation,
+	/// Envelope time scales from velocity and key, set at note on.
+	amp_scale: f64,
+	flt_scale: f64,
+	pressure: Sample,
+	timbre: Sample,
+	bend: Smoother,
+	just: Smoother,
+	channel: u8,
 	num: i8,
 }
 impl Note {
 	pub fn new() -> Note {
 		Note {
 			phase: Counter::new(),
+			freq: 0.,
 			amp: 0.,
-			amp_env: ADSR::new(),
+			amp_env: ContourState::new(),
 			flt: 0.,
-			flt_env: ADSR::new(),
+			flt_env: ContourState::new(),
+			shaper: Shaper::new(),
+			svf: Svf::new(),
+			ladder: Ladder::new(),
+			formant: Formant::new(),
+			lfo: LfoState::new(),
+			random: 0.,
 			down: false,
 			vel: 0.,
+			vel_mods: Modulation::new(),
+			amp_scale: 1.,
+			flt_scale: 1.,
+			pressure: 0.,
+			timbre: 0.,
+			bend: Smoother::new(BEND_SMOOTHING),
+			just: Smoother::new(0.),
+			channel: 0,
 			num: 0,
 		}
 	}
@@ -201,6 +268,13 @@ impl SampleRated for Note {
 		self.phase.set_sample_rate(sample_rate);
 		self.amp_env.set_sample_rate(sample_rate);
 		self.flt_env.set_sample_rate(sample_rate);
+		self.shaper.set_sample_rate(sample_rate);
+		self.svf.set_sample_rate(sample_rate);
+		self.ladder.set_sample_rate(sample_rate);
+		self.formant.set_sample_rate(sample_rate);
+		self.lfo.set_sample_rate(sample_rate);
+		self.bend.set_sample_rate(sample_rate);
+		self.just.set_sample_rate(sample_rate);
 	}
 }
 
@@ -215,20 +289,37 @@ pub struct Oscillator {
 
 	clk: usize,
 	
-	//amp: f64, pitchbend: f64, bendInt: f64,
+	//amp: f64,
+	patch: Patch,
+	bend: f64,
+	pitchbend: Smoother,
+	bend_ratio: f64,
+	pressure: Sample,
+	timbre: Sample,
+	mpe: Mpe,
+	expression: [Expression; 16],
+	sample_rate: Frequency,
+	vibrato_phase: f64,
+	vibrato_incr: f64,
+	lfo: LfoState,
+	bpm: f64,
+	controllers: [Sample; 128],
 	//dist: f64, fLP: f64, fHP: f64, qLP: f64, qHP: f64,
 	
-	//lfo: WaveTable,
-	//lfoNote: Note,
-	wf: WaveTable,
+	waveform: Waveforms,
+	wf: &'static WaveTable,
+	wf_morph: &'static WaveTable,
 	
 	tuning_preset: Tuning,
 	temperament: TuningData,
+	adaptive: Option<AdaptiveTuning>,
+	fine_tuning: Cents,
+	coarse_tuning: Semitones,
+	tuning_bank: u8,
+	rpn: Vec<ParameterState>,
 	sus: i8, poly: usize,
 	//low_note: usize, high_note: usize, cur_note: usize,
 	//hi_assign: usize, lo_assign: usize,
-	//lfof: f64,
-	//lfo2lp: f64, lfo2hp: f64, lfo2amp: f64, env2lp: f64,
 
 	notes: Vec<Option<Box<Note>>>,
 	active_notes: VecDeque<Box<Note>>,
@@ -241,6 +332,13 @@ impl SampleRated for Oscillator {
 				note.set_sample_rate(sample_rate);
 			}
 		}
+		for note in self.active_notes.iter_mut() {
+			note.set_sample_rate(sample_rate);
+		}
+		self.pitchbend.set_sample_rate(sample_rate);
+		self.lfo.set_sample_rate(sample_rate);
+		self.sample_rate = sample_rate as Frequency;
+		self.calc_vibrato();
 		self.retemper();
 	}
 }
@@ -252,13 +350,35 @@ impl Oscillator {
 			active_notes: VecDeque::new(),
 			tuning_preset: Tuning::EquaTemp,
 			temperament: TuningData::new(Tuning::EquaTemp),
+			adaptive: None,
+			fine_tuning: 0.,
+			coarse_tuning: 0.,
+			tuning_bank: 0,
+			rpn: vec![ParameterState::new(); 16],
 			poly: 0,
 			//low_note: 0, high_note: 127, cur_note: 0,
 			sus: 0,
 			active: false,
 			clk: 0,
-			wf: WaveTable::new(waveform),
+			patch: Patch::new(),
+			bend: 0.,
+			pitchbend: Smoother::new(BEND_SMOOTHING),
+			bend_ratio: 1.,
+			pressure: 0.,
+			timbre: 0.,
+			mpe: Mpe::new(),
+			expression: [Expression::new(); 16],
+			sample_rate: 0.,
+			vibrato_phase: 0.,
+			vibrato_incr: 0.,
+			lfo: LfoState::new(),
+			bpm: 120.,
+			controllers: [0.; 128],
+			waveform: waveform,
+			wf: &WAVEFORMS[&waveform],
+			wf_morph: &WAVEFORMS[&Waveforms::Saw],
 		};
+		osc.set_patch(Patch::new());
 		osc.retemper();
 		osc.active = true;
 		//osc.note_on(64,120);
@@ -267,37 +387,68 @@ impl Oscillator {
 	fn retemper(&mut self) {
 		println!("Oscillator::retemper");
 		self.temperament = super::temperament::TUNINGS[self.tuning_preset];
+		let offset = self.coarse_tuning * 100. + self.fine_tuning;
+		if offset != 0. {
+			let a = self.temperament.freq_a * (2.0 as Frequency).powf(offset / 1200.);
+			self.temperament.retune(a);
+		}
 		//println!("{}", self.temperament);
 		let mut n: usize = 0;
 		for note1 in self.notes.iter_mut() {
 			for note in note1.iter_mut() {
 				note.num = n as i8;
-				note.phase.set_freq(self.temperament.lookup(n as i8));
-				println!("{:?}", note);
+				note.freq = self.temperament.lookup(n as i8);
+				note.phase.set_freq(note.freq * self.bend_ratio);
 			}
 			n += 1;
 		}
+		for note in self.active_notes.iter_mut() {
+			note.freq = self.temperament.lookup(note.num);
+			note.phase.set_freq(note.freq * self.bend_ratio);
+		}
 	}
-	fn note_off(&mut self, n: i8) {
-		self.poly -= 1;
-		let un = n as usize;
+	pub fn set_tuning(&mut self, preset: Tuning) {
+		self.tuning_preset = preset;
+		self.retemper();
+	}
+	/// Channel fine tuning (RPN 1), within a semitone either way.
+	pub fn set_fine_tuning(&mut self, cents: Cents) {
+		self.fine_tuning = cents;
+		self.retemper();
+	}
+	/// Channel coarse tuning (RPN 2), in whole semitones.
+	pub fn set_coarse_tuning(&mut self, semitones: Semitones) {
+		self.coarse_tuning = semitones;
+		self.retemper();
+	}
+	/// Releases a held note. It stays in `active_notes` until its release
+	/// has finished, see `reclaim_notes`.
+	fn note_off(&mut self, n: i8, v: i8) {
+		self.voice_off(0, n, v);
+	}
+	/// Releases the note `n` started on `channel`, which is 0 for every
+	/// channel but the MPE member channels.
+	fn voice_off(&mut self, channel: u8, n: i8, v: i8) {
 		//let note: &mut Note = &mut self.notes[un].unwrap();
-
-		let mut n_off = 0;
-		for note in self.active_notes.iter() {
-			if note.num == n {
-				let mut note = self.active_notes.remove(n_off as usize).unwrap();
-
-				note.down = false;
-				if self.sus < 64 {
-					note.amp_env.gate_close();
-					note.flt_env.gate_close();
-				}
-				self.notes[un].replace(note);
-				break;
-			}
-			n_off += 1;
+		let note = match self.active_notes.iter_mut().find(|note| note.num == n && note.channel == channel && note.down) {
+			Some(note) => note,
+			None => return,
+		};
+		note.down = false;
+		let mut mods = Modulation::new();
+		self.patch.release_velocity.apply(v as Sample / 127., &mut mods);
+		if mods != Modulation::new() {
+			let (amp, flt, release) = (note.amp_scale, note.flt_scale, mods.time_scale());
+			note.amp_env.scale_times(amp, amp, amp * release);
+			note.flt_env.scale_times(flt, flt, flt * release);
+			note.vel_mods += mods;
 		}
+		if self.sus < 64 {
+			note.amp_env.gate_close();
+			note.flt_env.gate_close();
+		}
+		self.poly -= 1;
+		self.calc_chord_ratio(None);
 
 		/*
 		if self.high_note == n as usize {
@@ -330,22 +481,67 @@ impl Oscillator {
 		*/
 	}
 	fn note_on(&mut self, n: i8, v: i8) {
+		self.voice_on(0, n, v);
+	}
+	/// Starts note `n` on `channel`. Each MPE member channel has voices of
+	/// its own, so the same key held on two of them sounds twice.
+	fn voice_on(&mut self, channel: u8, n: i8, v: i8) {
 		//self.notes[n].freq  = calc_freq(n); // this should already be precomputed.
 		//self.notes[n].phase = 0; // let the piano class do this itself.	
 		//self.notes[n].time  = 0;
 		let un = n as usize;
 		//self.cur_note = un;
-		let mut note: Box<Note> = self.notes[un].take().unwrap();
+		// a note still sounding is struck again in place, so its envelopes
+		// carry on from their current level as the patch's retrigger says
+		let mut note: Box<Note> = match self.active_notes.iter().position(|note| note.num == n && note.channel == channel) {
+			Some(i) => self.active_notes.remove(i).unwrap(),
+			None => {
+				let mut note = match self.notes[un].take() {
+					Some(note) => note,
+					None => self.spare_note(n),
+				};
+				note.shaper.reset();
+				note.svf.reset();
+				note.ladder.reset();
+				note.formant.reset();
+				note
+			},
+		};
+		let was_down = note.down;
 		note.num   = n;
-		note.amp_env.gate_open();
-		note.flt_env.gate_open();
-		note.down  = true;
+		note.phase.set_freq(note.freq * self.bend_ratio);
+		note.pressure = 0.;
+		note.timbre = 0.;
+		note.bend.reset(0.);
+		note.channel = channel;
+		note.amp_env.set_contour(&self.patch.amp_env);
+		note.flt_env.set_contour(&self.patch.flt_env);
+		note.amp_env.set_retrigger(self.patch.retrigger);
+		note.flt_env.set_retrigger(self.patch.retrigger);
 
 		if v >= 0 {
-			note.vel = v as Sample / 127.;
+			let vel = v as Sample / 127.;
+			note.vel = self.patch.velocity.curve.apply(vel);
+			note.vel_mods = Modulation::new();
+			self.patch.velocity.apply(vel, &mut note.vel_mods);
+			let scale = note.vel_mods.time_scale();
+			note.amp_scale = scale * self.patch.amp_env_scaling.scale(n, vel);
+			note.flt_scale = scale * self.patch.flt_env_scaling.scale(n, vel);
+			note.amp_env.scale_times(note.amp_scale, note.amp_scale, note.amp_scale);
+			note.flt_env.scale_times(note.flt_scale, note.flt_scale, note.flt_scale);
 		}	
+		// after the scaling, which a multi-stage envelope takes up as it
+		// enters each segment
+		note.amp_env.gate_open();
+		note.flt_env.gate_open();
+		note.down  = true;
 
-		if self.poly < MAX_POLY - 1 { self.poly += 1; }
+		note.lfo.trigger(&self.patch.voice_lfo);
+		note.random = rand::thread_rng().gen();
+		if self.poly == 0 {
+			self.lfo.trigger(&self.patch.lfo);
+		}
+		if !was_down && self.poly < MAX_POLY - 1 { self.poly += 1; }
 
 		self.active_notes.push_front(note);
 		/*
@@ -357,21 +553,306 @@ impl Oscillator {
 		}
 		*/
 
-		// self.calc_chord_ratio();
+		self.calc_chord_ratio(Some((channel, n)));
 
 		self.active = true;
 		println!("{}", self.active_notes.len());
 	}
+	/// Starts a note on an MPE member channel, picking up the expression
+	/// already sent on that channel.
+	fn member_note_on(&mut self, channel: u8, n: i8, v: i8) {
+		self.voice_on(channel, n, v);
+		let range = match self.mpe.zone(channel) {
+			Some((zone, _)) => zone.bend_range,
+			None => return,
+		};
+		let expr = self.expression[channel as usize];
+		let note = self.active_notes.front_mut().unwrap();
+		note.bend.reset(expr.bend * range);
+		note.pressure = expr.pressure;
+		note.timbre = expr.timbre;
+	}
+	/// Another voice for note `n`, when its own is already sounding on
+	/// a different channel.
+	fn spare_note(&self, n: i8) -> Box<Note> {
+		let mut note = Box::new(Note::new());
+		note.set_sample_rate(self.sample_rate as SampleRate);
+		note.num = n;
+		note.freq = self.temperament.lookup(n);
+		note
+	}
+	/// The channel voices are kept apart by: the member channel for MPE,
+	/// otherwise 0.
+	fn voice_channel(&self, channel: u8) -> u8 {
+		if self.mpe.is_member(channel) { channel } else { 0 }
+	}
+	pub fn mpe(&self) -> &Mpe {
+		&self.mpe
+	}
+	/// Pitch bend on an MPE member channel, which only bends its own notes.
+	fn member_bend(&mut self, channel: u8, value: u16) {
+		let range = match self.mpe.zone(channel) {
+			Some((zone, _)) => zone.bend_range,
+			None => return,
+		};
+		let bend = Self::bend_amount(value);
+		self.expression[channel as usize].bend = bend;
+		for note in self.active_notes.iter_mut() {
+			if note.channel == channel {
+				note.bend.set(bend * range);
+			}
+		}
+	}
+	fn member_pressure(&mut self, channel: u8, value: u8) {
+		let pressure = value as Sample / 127.;
+		self.expression[channel as usize].pressure = pressure;
+		for note in self.active_notes.iter_mut() {
+			if note.channel == channel {
+				note.pressure = pressure;
+			}
+		}
+	}
+	fn member_timbre(&mut self, channel: u8, value: u8) {
+		let timbre = value as Sample / 127.;
+		self.expression[channel as usize].timbre = timbre;
+		for note in self.active_notes.iter_mut() {
+			if note.channel == channel {
+				note.timbre = timbre;
+			}
+		}
+	}
+	/// Switches adaptive just intonation on or off. Voices glide back to
+	/// the plain temperament when it is switched off.
+	pub fn set_adaptive(&mut self, adaptive: Option<AdaptiveTuning>) {
+		self.adaptive = adaptive;
+		if adaptive.is_none() {
+			for note in self.active_notes.iter_mut() {
+				note.just.set(0.);
+			}
+		}
+		self.calc_chord_ratio(None);
+	}
+	pub fn adaptive(&self) -> Option<&AdaptiveTuning> {
+		self.adaptive.as_ref()
+	}
+	/// Retunes the held chord. `fresh` is a note that has only just
+	/// started, which jumps straight to its pitch instead of gliding.
+	fn calc_chord_ratio(&mut self, fresh: Option<(u8, i8)>) {
+		let adaptive = match self.adaptive.as_mut() {
+			Some(adaptive) => adaptive,
+			None => return,
+		};
+		let mut keys = vec![];
+		let mut current = vec![];
+		for note in self.active_notes.iter().filter(|note| note.down) {
+			keys.push(note.num);
+			current.push(if Some((note.channel, note.num)) == fresh { None } else { Some(note.just.target()) });
+		}
+		let offsets = adaptive.retune(&self.temperament, &keys, &current);
+		let glide = adaptive.glide;
+		for (note, offset) in self.active_notes.iter_mut().filter(|note| note.down).zip(offsets) {
+			note.just.set_time(glide);
+			if Some((note.channel, note.num)) == fresh {
+				note.just.reset(offset);
+			} else {
+				note.just.set(offset);
+			}
+		}
+	}
+	/// Turns macro knob `n`, from 0 to 1. Knobs past `MACROS` are ignored.
+	pub fn set_macro(&mut self, n: usize, value: Sample) {
+		if let Some(knob) = self.patch.macros.get_mut(n) {
+			knob.value = value.max(0.).min(1.);
+		}
+	}
+	/// Tempo for LFOs synced to a note division.
+	pub fn set_bpm(&mut self, bpm: f64) {
+		self.bpm = bpm;
+	}
+	pub fn bpm(&self) -> f64 {
+		self.bpm
+	}
+	pub fn set_waveform(&mut self, waveform: Waveforms) {
+		self.waveform = waveform;
+		self.wf = &WAVEFORMS[&waveform];
+	}
+	/// Notes sounding, including those still in their release.
+	pub fn active_voices(&self) -> usize {
+		self.active_notes.len()
+	}
+	pub fn held_notes(&self) -> usize {
+		self.active_notes.iter().filter(|note| note.down).count()
+	}
+	pub fn patch(&self) -> &Patch {
+		&self.patch
+	}
+	pub fn set_patch(&mut self, patch: Patch) {
+		self.patch = patch;
+		self.wf_morph = &WAVEFORMS[&patch.wave_morph];
+		self.update_bend();
+		self.calc_vibrato();
+	}
+	/// Polyphonic key pressure, which only reaches the voice playing `n`.
+	pub fn poly_pressure(&mut self, n: i8, value: u8) {
+		self.key_pressure(0, n, value);
+	}
+	fn key_pressure(&mut self, channel: u8, n: i8, value: u8) {
+		for note in self.active_notes.iter_mut() {
+			if note.num == n && note.channel == channel {
+				note.pressure = value as Sample / 127.;
+			}
+		}
+	}
+	/// Channel pressure, shared by every voice.
+	pub fn channel_pressure(&mut self, value: u8) {
+		self.pressure = value as Sample / 127.;
+	}
+	/// Sets the pitch bend sensitivity, as sent by RPN 0.
+	pub fn set_bend_range(&mut self, range: Semitones) {
+		self.patch.bend_range = range;
+		self.update_bend();
+	}
+	/// Takes a raw 14-bit pitch bend value, centered on 8192.
+	pub fn pitch_bend(&mut self, value: u16) {
+		self.bend = Self::bend_amount(value);
+		self.update_bend();
+	}
+	fn bend_amount(value: u16) -> f64 {
+		let v = value as f64 - BEND_CENTER;
+		if v < 0. { v / BEND_CENTER } else { v / (BEND_CENTER - 1.) }
+	}
+	fn control_change(&mut self, channel: u8, control: u8, value: u8) {
+		if !self.mpe.is_member(channel) {
+			self.controllers[(control & 0x7f) as usize] = value as Sample / 127.;
+			for knob in self.patch.macros.iter_mut().filter(|knob| knob.cc == Some(control)) {
+				knob.value = value as Sample / 127.;
+			}
+		}
+		match control {
+			DATA_ENTRY_MSB | DATA_ENTRY_LSB | DATA_INCREMENT | DATA_DECREMENT |
+			NRPN_LSB | NRPN_MSB | RPN_LSB | RPN_MSB => {
+				if let Some((param, value)) = self.rpn[channel as usize].control_change(control, value) {
+					self.parameter_change(channel, param, value);
+				}
+			},
+			CC_SLIDE => {
+				if self.mpe.is_member(channel) {
+					self.member_timbre(channel, value);
+				} else {
+					self.timbre = value as Sample / 127.;
+				}
+			},
+			_ => {},
+		}
+	}
+	fn parameter_change(&mut self, channel: u8, param: Parameter, value: u16) {
+		let msb = (value >> 7) as u8;
+		let lsb = (value & 0x7f) as u8;
+		match param {
+			Parameter::Rpn(RPN_BEND_RANGE) => {
+				let range = msb as Semitones + lsb as Semitones / 100.;
+				if self.mpe.is_member(channel) {
+					self.mpe.zone_mut(channel).unwrap().bend_range = range;
+				} else {
+					self.set_bend_range(range);
+				}
+			},
+			Parameter::Rpn(RPN_FINE_TUNING) => {
+				self.set_fine_tuning((value as Cents - BEND_CENTER) / BEND_CENTER * 100.);
+			},
+			Parameter::Rpn(RPN_COARSE_TUNING) => {
+				self.set_coarse_tuning(msb as Semitones - 64.);
+			},
+			Parameter::Rpn(RPN_TUNING_PROGRAM) => {
+				match Tuning::from_program(msb) {
+					Some(preset) if self.tuning_bank == 0 => self.set_tuning(preset),
+					_ => println!("No tuning program {} in bank {}", msb, self.tuning_bank),
+				}
+			},
+			Parameter::Rpn(RPN_TUNING_BANK) => {
+				self.tuning_bank = msb;
+			},
+			Parameter::Rpn(RPN_MPE_CONFIGURATION) => {
+				if !self.mpe.configure(channel, msb) {
+					println!("MPE configuration ignored on channel {}", channel + 1);
+				}
+			},
+			_ => {},
+		}
+	}
+	fn update_bend(&mut self) {
+		self.pitchbend.set(self.bend * self.patch.bend_range);
+	}
+	fn do_bend(&mut self) {
+		if self.pitchbend.is_settled() {
+			return;
+		}
+		self.bend_ratio = (2.0 as f64).powf(self.pitchbend.run() / 12.);
+	}
+	fn calc_vibrato(&mut self) {
+		if self.sample_rate > 0. {
+			self.vibrato_incr = self.patch.vibrato_rate / self.sample_rate;
+		}
+	}
+	fn do_vibrato(&mut self) -> Sample {
+		self.vibrato_phase += self.vibrato_incr;
+		if self.vibrato_phase >= 1. {
+			self.vibrato_phase -= 1.;
+		}
+		(2. * PI * self.vibrato_phase).sin()
+	}
+	fn do_modulation(patch: &Patch, pressure: Sample, timbre: Sample, note: &Note) -> Modulation {
+		let mut mods = note.vel_mods;
+		patch.poly_pressure.apply(note.pressure, &mut mods);
+		patch.channel_pressure.apply(pressure, &mut mods);
+		patch.slide.apply((note.timbre + timbre).min(1.), &mut mods);
+		mods
+	}
+	fn do_filter(patch: &Patch, note: &mut Note, mods: &Modulation, x: Sample) -> Sample {
+		let filter = &patch.filter;
+		if filter.kind == Kind::Off {
+			return x;
+		}
+		let cutoff = filter.voice_cutoff(note.num, note.flt, note.vel, mods.cutoff);
+		let resonance = filter.resonance + mods.resonance;
+		match filter.kind {
+			Kind::Off => x,
+			Kind::Svf(mode) => {
+				note.svf.set_mode(mode);
+				note.svf.set(cutoff, resonance);
+				note.svf.process(x)
+			},
+			Kind::Ladder(slope) => {
+				note.ladder.set_slope(slope);
+				note.ladder.set(cutoff, resonance, filter.drive);
+				note.ladder.process(x)
+			},
+			Kind::Formant(voice) => {
+				note.formant.set(voice, filter.vowel + filter.vowel_env * note.flt + mods.vowel);
+				note.formant.process(x)
+			},
+		}
+	}
 	fn do_adsr(note: &mut Note) {
 		note.amp = note.amp_env.run();
 		note.flt = note.flt_env.run();
 
 		if note.amp_env.is_off() {
 			note.flt_env.gate_close();
-			note.num = 0;
 			note.phase.set_phase(0);
 		}
 	}
+	/// Puts notes whose release has finished back in their slots.
+	fn reclaim_notes(&mut self) {
+		while let Some(i) = self.active_notes.iter().position(|note| note.amp_env.is_off()) {
+			let note = self.active_notes.remove(i).unwrap();
+			let un = note.num as usize;
+			// a spare from `spare_note` is dropped if the slot is taken
+			if self.notes[un].is_none() {
+				self.notes[un] = Some(note);
+			}
+		}
+	}
 }
 
 
@@ -379,12 +860,64 @@ impl Generator for Oscillator {
 	fn generate(&mut self) -> [f32; 2] {
 		//let mut o: Sample = 0.;
 		let mut left: Sample = 0.;
-		let right: Sample;
+		let mut right: Sample = 0.;
 
+		self.do_bend();
+		let vibrato = self.do_vibrato();
+		let lfo = self.lfo.run(&self.patch.lfo, self.bpm);
+		let saw = &WAVEFORMS[&Waveforms::Saw];
+		let macros = self.patch.macro_modulation();
 		for note in self.active_notes.iter_mut() {
 			Self::do_adsr(note);
-			left += self.wf.lookup(&mut note.phase) * note.amp * note.vel;
+			let mut mods = Self::do_modulation(&self.patch, self.pressure, self.timbre, note);
+			mods += macros;
+			self.patch.lfo.routing.apply_bipolar(lfo, &mut mods);
+			let voice_lfo = note.lfo.run(&self.patch.voice_lfo, self.bpm);
+			self.patch.voice_lfo.routing.apply_bipolar(voice_lfo, &mut mods);
+			let sources = Sources {
+				lfo,
+				voice_lfo,
+				amp_env: note.amp,
+				flt_env: note.flt,
+				velocity: note.vel,
+				key: note.num as Sample / 127.,
+				poly_pressure: note.pressure,
+				channel_pressure: self.pressure,
+				pitch_bend: self.bend,
+				random: note.random,
+				controllers: &self.controllers,
+			};
+			self.patch.matrix.apply(&sources, &mut mods);
+
+			let depth = self.patch.vibrato_depth + mods.vibrato;
+			let detune = note.bend.run() + note.just.run() / 100. + vibrato * depth + mods.pitch;
+			let mut ratio = self.bend_ratio;
+			if detune != 0. {
+				ratio *= (2.0 as f64).powf(detune / 12.);
+			}
+			note.phase.set_freq(note.freq * ratio);
+
+			let pos = (self.patch.wave_position + mods.wave_position).max(0.).min(1.);
+			let wave = if self.waveform == Waveforms::Square {
+				let width = (self.patch.pulse_width + mods.pulse_width).max(0.01).min(0.99);
+				let y = saw.pulse(width, &note.phase) * (1. - pos) + self.wf_morph.peek(&note.phase) * pos;
+				note.phase.increment();
+				y
+			} else {
+				self.wf.morph(self.wf_morph, pos, &mut note.phase)
+			};
+			let wave = note.shaper.process(&self.patch.distortion, wave);
+			let out = Self::do_filter(&self.patch, note, &mods, wave) * note.amp * note.vel * mods.gain();
+			if mods.pan == 0. {
+				left += out;
+				right += out;
+			} else {
+				let [l, r] = pan_gains(mods.pan);
+				left += out * l;
+				right += out * r;
+			}
 		}
+		self.reclaim_notes();
 		self.clk += 1;
 
 		if self.clk % 96000 == 0 {
@@ -394,30 +927,68 @@ impl Generator for Oscillator {
 		//o = applyEffects(left);
 		//o = left;
 		//left = o;// * self.pan.amp_l;
-		right = left * 1.;// * self.pan.amp_r;
+		//right = left * 1.;// * self.pan.amp_r;
 		
 		[left as f32, right as f32]
 	}
 }
+impl Tempo for Oscillator {
+	fn set_bpm(&mut self, bpm: f64) {
+		Oscillator::set_bpm(self, bpm);
+	}
+}
+impl Macros for Oscillator {
+	fn macros(&self) -> Option<&[Macro; MACROS]> {
+		Some(&self.patch.macros)
+	}
+}
 impl MidiDispatcher for Oscillator {
 	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
 		use midistream::*;
 		match msg {
 			Msg::Simple(x) => match x {
-				SimpleMsg::NoteOn(y) => {
+				SimpleMsg::NoteOn(y) if *y.value > 0 => {
 					println!("Note on {:?}", y);
 					let note: u8  = *y.note;
 					let value: u8 = *y.value;
-					self.note_on(note.try_into().unwrap(), value.try_into().unwrap());
+					if self.mpe.is_member(*y.channel) {
+						self.member_note_on(*y.channel, note.try_into().unwrap(), value.try_into().unwrap());
+					} else {
+						self.note_on(note.try_into().unwrap(), value.try_into().unwrap());
+					}
 				},
+				// a note on at velocity 0 is a note off
+				SimpleMsg::NoteOn(y) |
 				SimpleMsg::NoteOff(y) => {
 					println!("Note off {:?}", y);
 					let note: u8  = *y.note;
-					self.note_off(note.try_into().unwrap());
+					let value: u8 = *y.value;
+					let channel = self.voice_channel(*y.channel);
+					self.voice_off(channel, note.try_into().unwrap(), value.try_into().unwrap());
+				},
+				SimpleMsg::PolyKeyPressure(y) => {
+					let note: u8 = *y.note;
+					let channel = self.voice_channel(*y.channel);
+					self.key_pressure(channel, note.try_into().unwrap(), *y.value);
 				},
-				y => {
-					println!("{:?}", y);
+				SimpleMsg::ChannelKeyPressure(y) => {
+					if self.mpe.is_member(*y.channel) {
+						self.member_pressure(*y.channel, *y.value);
+					} else {
+						self.channel_pressure(*y.value);
+					}
 				},
+				SimpleMsg::PitchBendChange(y) => {
+					if self.mpe.is_member(*y.channel) {
+						self.member_bend(*y.channel, *y.value);
+					} else {
+						self.pitch_bend(*y.value);
+					}
+				},
+				SimpleMsg::ControlChange(y) => {
+					self.control_change(*y.channel, *y.control, *y.value);
+				},
+				_ => {},
 			},
 			Msg::Complex(x) => {
 				println!("Received {:?}", x);
@@ -493,4 +1064,475 @@ fn test_counter() {
 	c.increment(); assert_eq!(c.int(), size*6/8);
 	c.increment(); assert_eq!(c.int(), size*7/8);
 	c.increment(); assert_eq!(c.int(), 0);
-}
\ No newline at end of file
+}
+/// Sets a registered parameter the way a controller does, selecting it with
+/// CC 101 and 100 and then sending data entry CC 6 and 38.
+#[cfg(test)]
+fn send_rpn(osc: &mut Oscillator, channel: u8, param: u16, value: u16) {
+	use midistream::SimpleMsg;
+	for &(cc, v) in &[(RPN_MSB, param >> 7), (RPN_LSB, param & 0x7f), (DATA_ENTRY_MSB, value >> 7), (DATA_ENTRY_LSB, value & 0x7f)] {
+		osc.dispatch_midi_in(&SimpleMsg::control_change(channel, cc, v as u8).into());
+	}
+}
+
+#[test]
+fn test_pitch_bend() {
+	let rate = 48000;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(rate);
+	osc.note_on(69, 100);
+	let incr = osc.active_notes[0].phase.incr.0 as f64;
+
+	send_rpn(&mut osc, 0, RPN_BEND_RANGE, 12 << 7);
+	assert_eq!(osc.patch().bend_range, 12.);
+	osc.dispatch_midi_in(&midistream::SimpleMsg::pitch_bend_change(0, 16383).into());
+	let v = osc.generate()[0]; assert!(v.abs() < 1.);
+	let early = osc.active_notes[0].phase.incr.0 as f64;
+	assert!(early > incr && early < incr * 1.1, "bend should be smoothed");
+	for _ in 0..rate { osc.generate(); }
+	let bent = osc.active_notes[0].phase.incr.0 as f64;
+	assert!((bent / incr - 2.).abs() < 1e-6, "ratio = {}", bent / incr);
+
+	osc.pitch_bend(8192);
+	for _ in 0..rate { osc.generate(); }
+	assert_eq!(osc.active_notes[0].phase.incr.0 as f64, incr);
+}
+
+#[test]
+fn test_master_tuning() {
+	use midistream::SimpleMsg;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	assert_eq!(osc.notes[69].as_ref().unwrap().freq, 440.);
+	for (cc, value) in &[(RPN_MSB, 0), (RPN_LSB, RPN_COARSE_TUNING as u8), (DATA_ENTRY_MSB, 64 + 12)] {
+		osc.dispatch_midi_in(&SimpleMsg::control_change(3, *cc, *value).into());
+	}
+	assert_eq!(osc.notes[69].as_ref().unwrap().freq, 880.);
+
+	osc.dispatch_midi_in(&SimpleMsg::control_change(3, RPN_LSB, RPN_FINE_TUNING as u8).into());
+	osc.dispatch_midi_in(&SimpleMsg::control_change(3, DATA_ENTRY_MSB, 0).into());
+	let flat = 880. * (2.0 as Frequency).powf(-1. / 12.);
+	assert!((osc.notes[69].as_ref().unwrap().freq - flat).abs() < 1e-9);
+
+	osc.dispatch_midi_in(&SimpleMsg::control_change(3, RPN_LSB, RPN_TUNING_PROGRAM as u8).into());
+	osc.dispatch_midi_in(&SimpleMsg::control_change(3, DATA_ENTRY_MSB, 6).into());
+	assert_eq!(osc.tuning_preset, Tuning::PtolTemp);
+}
+
+#[test]
+fn test_aftertouch() {
+	use midistream::SimpleMsg;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	let mut patch = Patch::new();
+	patch.poly_pressure.amplitude = -1.;
+	patch.channel_pressure.wave_position = 1.;
+	osc.set_patch(patch);
+	osc.note_on(60, 100);
+	osc.note_on(64, 100);
+
+	osc.dispatch_midi_in(&SimpleMsg::poly_key_pressure(0, 64, 127).into());
+	for note in osc.active_notes.iter() {
+		let mods = Oscillator::do_modulation(&osc.patch, osc.pressure, osc.timbre, note);
+		assert_eq!(mods.gain(), if note.num == 64 { 0. } else { 1. });
+		assert_eq!(mods.wave_position, 0.);
+	}
+
+	osc.dispatch_midi_in(&SimpleMsg::channel_key_pressure(0, 127).into());
+	for note in osc.active_notes.iter() {
+		let mods = Oscillator::do_modulation(&osc.patch, osc.pressure, osc.timbre, note);
+		assert_eq!(mods.wave_position, 1.);
+	}
+}
+
+#[test]
+fn test_mpe() {
+	use midistream::SimpleMsg;
+	let rate = 48000;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(rate);
+	osc.set_patch(Patch::mpe());
+	send_rpn(&mut osc, 0, RPN_MPE_CONFIGURATION, 15 << 7);
+	assert_eq!(osc.mpe().lower.members, 15);
+
+	osc.dispatch_midi_in(&SimpleMsg::pitch_bend_change(2, 16383).into());
+	osc.dispatch_midi_in(&SimpleMsg::control_change(2, CC_SLIDE, 127).into());
+	osc.dispatch_midi_in(&SimpleMsg::note_on(2, 60, 100).into());
+	osc.dispatch_midi_in(&SimpleMsg::note_on(3, 64, 100).into());
+	let incr = |osc: &Oscillator, n: i8| {
+		osc.active_notes.iter().find(|note| note.num == n).unwrap().phase.incr.0 as f64
+	};
+	osc.generate();
+	// the full 48 semitone member range
+	let bent = osc.temperament.lookup(108) * osc.notes[0].as_ref().unwrap().phase.dsr;
+	assert!((incr(&osc, 60) - bent).abs() < 2.);
+	let plain = osc.temperament.lookup(64) * osc.notes[0].as_ref().unwrap().phase.dsr;
+	assert!((incr(&osc, 64) - plain).abs() < 2.);
+
+	osc.dispatch_midi_in(&SimpleMsg::channel_key_pressure(3, 127).into());
+	for note in osc.active_notes.iter() {
+		let mods = Oscillator::do_modulation(&osc.patch, osc.pressure, osc.timbre, note);
+		assert_eq!(mods.wave_position, if note.num == 60 { 1. } else { 0. });
+		assert_eq!(mods.gain(), if note.num == 64 { 1.5 } else { 1. });
+	}
+}
+
+#[test]
+fn test_mpe_same_key() {
+	use midistream::SimpleMsg;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	send_rpn(&mut osc, 0, RPN_MPE_CONFIGURATION, 15 << 7);
+	// two fingers on the same pitch, each with its own voice
+	osc.dispatch_midi_in(&SimpleMsg::note_on(2, 60, 100).into());
+	osc.dispatch_midi_in(&SimpleMsg::note_on(3, 60, 100).into());
+	assert_eq!(osc.held_notes(), 2);
+	osc.dispatch_midi_in(&SimpleMsg::channel_key_pressure(3, 127).into());
+	osc.dispatch_midi_in(&SimpleMsg::note_off(2, 60, 0).into());
+	assert_eq!(osc.held_notes(), 1);
+	let held = osc.active_notes.iter().find(|note| note.down).unwrap();
+	assert_eq!((held.channel, held.pressure), (3, 1.));
+
+	osc.dispatch_midi_in(&SimpleMsg::note_off(3, 60, 0).into());
+	assert_eq!(osc.held_notes(), 0);
+	for _ in 0..48000 { osc.generate(); }
+	assert_eq!(osc.active_voices(), 0);
+	assert!(osc.notes[60].is_some());
+}
+
+#[test]
+fn test_adaptive_tuning() {
+	let rate = 48000;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(rate);
+	osc.set_adaptive(Some(AdaptiveTuning::new()));
+	osc.note_on(60, 100);
+	osc.note_on(67, 100);
+	osc.generate();
+	let incr = |osc: &Oscillator, n: i8| {
+		osc.active_notes.iter().find(|note| note.num == n).unwrap().phase.incr.0 as f64
+	};
+	assert!((incr(&osc, 67) / incr(&osc, 60) - 1.5).abs() < 1e-6);
+
+	// the fifth stays put while the new third lands a pure 5:4 above C
+	osc.note_on(64, 100);
+	osc.generate();
+	assert!((incr(&osc, 64) / incr(&osc, 60) - 1.25).abs() < 1e-6);
+	assert!((incr(&osc, 67) / incr(&osc, 60) - 1.5).abs() < 1e-6);
+
+	osc.set_adaptive(None);
+	for _ in 0..rate { osc.generate(); }
+	let equal = (2.0 as f64).powf(4. / 12.);
+	assert!((incr(&osc, 64) / incr(&osc, 60) - equal).abs() < 1e-6);
+
+	// with a fixed root, letting go of the whole chord leaves nothing to tune
+	let mut fixed = AdaptiveTuning::new();
+	fixed.root = Some(0);
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(rate);
+	osc.set_adaptive(Some(fixed));
+	for &n in &[60, 64, 67] {
+		osc.note_on(n, 100);
+	}
+	osc.generate();
+	for &n in &[60, 64, 67] {
+		osc.note_off(n, 0);
+	}
+	osc.generate();
+	osc.set_adaptive(Some(fixed));
+}
+
+#[test]
+fn test_velocity() {
+	use super::modulation::{Curve, Routing};
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	let mut patch = Patch::new();
+	patch.velocity = Routing { curve: Curve::Exponential, env_time: -2., wave_position: 1., ..Routing::new() };
+	patch.release_velocity.env_time = -1.;
+	osc.set_patch(patch);
+
+	osc.note_on(60, 127);
+	osc.note_on(64, 1);
+	let soft = osc.active_notes.iter().find(|note| note.num == 64).unwrap();
+	let hard = osc.active_notes.iter().find(|note| note.num == 60).unwrap();
+	assert_eq!(hard.vel, 1.);
+	assert!(soft.vel < 1e-4);
+	assert_eq!(hard.vel_mods.wave_position, 1.);
+	assert_eq!(hard.vel_mods.time_scale(), 0.25);
+	assert!((soft.vel_mods.time_scale() - 1.).abs() < 1e-3);
+
+	let mut hard_env = hard.amp_env;
+	let mut plain = ADSR::new();
+	plain.set_sample_rate(48000);
+	plain.gate_open();
+	plain.run();
+	hard_env.run();
+	assert!((hard_env.run() - 4. * plain.run()).abs() < 1e-12, "attack should be four times faster");
+
+	osc.note_off(60, 127);
+	let released = osc.active_notes.iter().find(|note| note.num == 60).unwrap();
+	assert!(!released.down);
+	assert_eq!(released.vel_mods.env_time, -3.);
+
+	osc.set_patch(Patch { velocity: Routing { curve: Curve::Fixed(0.5), ..Routing::new() }, ..Patch::new() });
+	osc.note_on(67, 10);
+	assert_eq!(osc.active_notes[0].vel, 0.5);
+}
+
+#[test]
+fn test_note_on_zero_velocity() {
+	use super::modulation::{Curve, Routing};
+	use midistream::SimpleMsg;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(1000);
+	osc.set_patch(Patch { velocity: Routing { curve: Curve::Fixed(0.8), ..Routing::new() }, ..Patch::new() });
+	osc.dispatch_midi_in(&SimpleMsg::note_on(0, 60, 100).into());
+	assert_eq!(osc.held_notes(), 1);
+	// released, not struck again at the fixed velocity
+	osc.dispatch_midi_in(&SimpleMsg::note_on(0, 60, 0).into());
+	assert_eq!(osc.held_notes(), 0);
+	for _ in 0..1000 { osc.generate(); }
+	assert_eq!(osc.active_voices(), 0);
+	assert!(osc.notes[60].is_some());
+}
+
+#[test]
+fn test_release_and_retrigger() {
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(1000);
+	osc.note_on(60, 127);
+	for _ in 0..2000 { osc.generate(); }
+	osc.note_off(60, 0);
+	osc.note_off(60, 0);
+	assert_eq!(osc.held_notes(), 0);
+	assert_eq!(osc.active_voices(), 1, "the release should still sound");
+	osc.generate();
+	let level = osc.active_notes[0].amp;
+	assert!(level > 0. && level < 0.75);
+
+	// struck again while releasing, the same voice continues from its level
+	osc.note_on(60, 127);
+	assert_eq!(osc.active_voices(), 1);
+	osc.generate();
+	assert!(osc.active_notes[0].amp > level);
+	assert!(osc.active_notes[0].amp - level < 0.05, "no jump in level");
+
+	osc.note_off(60, 0);
+	for _ in 0..1000 { osc.generate(); }
+	assert_eq!(osc.active_voices(), 0);
+	assert!(osc.notes[60].is_some());
+}
+
+#[test]
+fn test_envelope_key_tracking() {
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	let mut patch = Patch::new();
+	patch.amp_env_scaling = TimeScaling { key_follow: -1., center_key: 60, velocity: 0. };
+	patch.flt_env_scaling.velocity = -1.;
+	osc.set_patch(patch);
+
+	osc.note_on(60, 127);
+	osc.note_on(84, 127);
+	let center = osc.active_notes.iter().find(|note| note.num == 60).unwrap();
+	let high = osc.active_notes.iter().find(|note| note.num == 84).unwrap();
+	assert_eq!(center.amp_scale, 1.);
+	assert_eq!(high.amp_scale, 0.25);
+	assert_eq!(high.flt_scale, 0.5);
+
+	let (mut center_env, mut high_env) = (center.amp_env, high.amp_env);
+	center_env.run();
+	high_env.run();
+	assert!((high_env.run() - 4. * center_env.run()).abs() < 1e-12, "two octaves up should attack four times faster");
+}
+
+#[test]
+fn test_voice_envelopes() {
+	use super::envelope::{Contour, Segment};
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(1000);
+	let mut patch = Patch::new();
+	patch.amp_env = Contour::Adsr {
+		attack: 0.1, decay: 0.1, sustain: 0.5, release: 0.1,
+		shapes: [Shape::Exponential, Shape::Linear, Shape::Linear],
+	};
+	// a 10 Hz triangle between 0.5 and 1 for as long as the key is held
+	patch.flt_env = Contour::mseg(&[
+		Segment::new(0.05, 1.),
+		Segment::new(0.05, 0.5),
+		Segment::new(0.05, 1.),
+		Segment::new(0.1, 0.),
+	], None, Some((1, 2)));
+	osc.set_patch(patch);
+
+	osc.note_on(60, 127);
+	let mut levels = vec![];
+	for _ in 0..300 {
+		osc.generate();
+		let note = &osc.active_notes[0];
+		levels.push((note.amp, note.flt));
+	}
+	// half way through the attack, well up the exponential curve
+	assert!((levels[49].0 - Shape::Exponential.apply(0.5)).abs() < 1e-9);
+	assert_eq!(levels[199].0, 0.5);
+	assert_eq!(levels[49].1, 1.);
+	for i in &[99, 199, 299] {
+		assert!((levels[*i].1 - 0.5).abs() < 1e-9);
+		assert!((levels[*i - 50].1 - 1.).abs() < 1e-9);
+	}
+
+	// the loop runs on to its end, then falls to zero, as does the ADSR
+	osc.note_off(60, 0);
+	for _ in 0..50 { osc.generate(); }
+	assert!((osc.active_notes[0].flt - 1.).abs() < 1e-9);
+	for _ in 0..100 { osc.generate(); }
+	assert_eq!(osc.active_voices(), 0);
+}
+
+#[test]
+fn test_voice_filter() {
+	use super::filter::{Filter, Mode};
+	fn level(osc: &mut Oscillator) -> Sample {
+		osc.note_on(69, 127);
+		let mut peak: Sample = 0.;
+		// past the attack and decay, into the sustain
+		for i in 0..60000 {
+			let y = osc.generate()[0] as Sample;
+			if i > 57600 { peak = peak.max(y.abs()); }
+		}
+		osc.note_off(69, 0);
+		for _ in 0..48000 { osc.generate(); }
+		peak
+	}
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	let open = level(&mut osc);
+
+	let filter = Filter { kind: Kind::Svf(Mode::Lowpass), cutoff: 55., ..Filter::new() };
+	osc.set_patch(Patch { filter, ..Patch::new() });
+	let closed = level(&mut osc);
+	assert!(closed < open * 0.05, "three octaves above the cutoff");
+
+	// the filter envelope sustains at 0.75, so 48 semitones opens it to 440 Hz
+	osc.set_patch(Patch { filter: Filter { env_amount: 48., ..filter }, ..Patch::new() });
+	let swept = level(&mut osc);
+	assert!(swept > open * 0.4 && swept < open * 0.6);
+}
+
+#[test]
+fn test_lfos() {
+	use super::lfo::{Lfo, Shape, Rate};
+	use super::tempo::Division;
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(1000);
+	osc.set_bpm(60.);
+	// a half note square at 60 bpm, one second up and one down, gating the
+	// amplitude
+	let mut lfo = Lfo { shape: Shape::Wave(Waveforms::Square), rate: Rate::Tempo(Division::Half), key_sync: true, ..Lfo::new() };
+	lfo.routing.amplitude = 1.;
+	osc.set_patch(Patch { lfo, ..Patch::new() });
+	osc.note_on(69, 127);
+	let out: Vec<f32> = (0..2000).map(|_| osc.generate()[0]).collect();
+	assert!(out[..1000].iter().any(|&y| y != 0.));
+	assert!(out[1000..].iter().all(|&y| y == 0.), "silenced while the LFO is down");
+
+	// per voice, a new note starts its own cycle while the first is silenced
+	osc.note_off(69, 0);
+	for _ in 0..2000 { osc.generate(); }
+	osc.set_patch(Patch { voice_lfo: lfo, ..Patch::new() });
+	osc.note_on(60, 127);
+	for _ in 0..1500 { osc.generate(); }
+	osc.note_on(64, 127);
+	let out: Vec<f32> = (0..400).map(|_| osc.generate()[0]).collect();
+	assert!(out.iter().any(|&y| y != 0.));
+}
+
+#[test]
+fn test_mod_matrix() {
+	use super::matrix::{Slot, Source, Destination};
+	use midistream::*;
+	let mut osc = Oscillator::new(Waveforms::Square);
+	osc.set_sample_rate(48000);
+	let mut patch = Patch::new();
+	patch.matrix.add(Slot::new(Source::ModWheel, Destination::Pan, 1.));
+	patch.matrix.add(Slot::new(Source::Cc(20), Destination::Pitch, 12.));
+	osc.set_patch(patch);
+	osc.dispatch_midi_in(&SimpleMsg::control_change(0, 20, 127).into());
+	assert_eq!(osc.controllers[20], 1.);
+
+	osc.note_on(57, 127);
+	let [l, r] = osc.generate();
+	assert_eq!(l, r, "centred without the mod wheel");
+	assert!((osc.active_notes[0].phase.incr.0 as f64 / osc.active_notes[0].phase.dsr - 440.).abs() < 0.01);
+
+	osc.dispatch_midi_in(&SimpleMsg::control_change(0, 1, 127).into());
+	for _ in 0..100 {
+		assert!(osc.generate()[0].abs() < 1e-6, "hard right");
+	}
+	assert!((0..1000).any(|_| osc.generate()[1] != 0.));
+}
+
+#[test]
+fn test_pulse_width() {
+	let saw = &WAVEFORMS[&Waveforms::Saw];
+	let mut phase = Counter::new();
+	phase.set_sample_rate(1000);
+	phase.set_freq(10.);
+	for width in &[0.5, 0.25, 0.1] {
+		phase.set_phase(0);
+		let high = (0..100).filter(|_| {
+			let y = saw.pulse(*width, &phase);
+			phase.increment();
+			assert!((y.abs() - 1.).abs() < 1e-5, "{}", y);
+			y > 0.
+		}).count();
+		assert!((high as f64 - width * 100.).abs() <= 1., "{} high for {}", high, width);
+	}
+}
+
+#[test]
+fn test_macros() {
+	use super::macros::Mapping;
+	use super::matrix::Destination;
+	use midistream::*;
+	let mut osc = Oscillator::new(Waveforms::Saw);
+	osc.set_sample_rate(48000);
+	let mut patch = Patch::new();
+	patch.macros[0].cc = Some(16);
+	patch.macros[0].map(Mapping::new(Destination::Cutoff, 0., 36.));
+	patch.macros[0].map(Mapping::new(Destination::Resonance, 0.8, 0.2));
+	patch.macros[1].map(Mapping::new(Destination::Pitch, 0., 12.));
+	osc.set_patch(patch);
+
+	osc.dispatch_midi_in(&SimpleMsg::control_change(0, 16, 127).into());
+	osc.set_macro(1, 0.5);
+	osc.set_macro(super::macros::MACROS, 1.);
+	let mods = osc.patch.macro_modulation();
+	assert_eq!(mods.cutoff, 36.);
+	assert!((mods.resonance - 0.2).abs() < 1e-12);
+	assert_eq!(mods.pitch, 6.);
+	assert_eq!(osc.patch().macros[0].value, 1.);
+}
+
+#[test]
+fn test_voice_distortion() {
+	use super::distortion::{Distortion, Shape};
+	fn crest(osc: &mut Oscillator) -> Sample {
+		osc.note_on(57, 127);
+		let out: Vec<Sample> = (0..60000).map(|_| osc.generate()[0] as Sample).collect();
+		osc.note_off(57, 0);
+		for _ in 0..48000 { osc.generate(); }
+		let sustain = &out[57600..];
+		let peak = sustain.iter().fold(0., |peak: Sample, y| peak.max(y.abs()));
+		let rms = (sustain.iter().map(|y| y * y).sum::<Sample>() / sustain.len() as Sample).sqrt();
+		rms / peak
+	}
+	let mut osc = Oscillator::new(Waveforms::Sine);
+	osc.set_sample_rate(48000);
+	assert!((crest(&mut osc) - 0.5_f64.sqrt()).abs() < 0.01);
+	// driven hard into the clipper a sine comes out nearly square
+	let distortion = Distortion { shape: Shape::HardClip, drive: 1., ..Distortion::new() };
+	osc.set_patch(Patch { distortion, ..Patch::new() });
+	assert!(crest(&mut osc) > 0.8);
+}
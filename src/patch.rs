@@ -0,0 +1,114 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::{Frequency, Sample, Semitones};
+use super::oscillator::Waveforms;
+use super::modulation::{Curve, Routing, Modulation};
+use super::adsr::{Retrigger, TimeScaling};
+use super::envelope::Contour;
+use super::filter::Filter;
+use super::distortion::Distortion;
+use super::lfo::Lfo;
+use super::matrix::Matrix;
+use super::macros::{Macro, MACROS};
+
+/// Sound parameters shared by every voice of an `Oscillator`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Patch {
+	pub bend_range: Semitones,
+	pub vibrato_rate: Frequency,
+	pub vibrato_depth: Semitones,
+	/// Waveform crossfaded in as the wavetable position moves towards 1.
+	pub wave_morph: Waveforms,
+	pub wave_position: Sample,
+	/// Duty cycle of the square wave.
+	pub pulse_width: Sample,
+	/// Per-voice pressure: polyphonic key pressure, or channel pressure on
+	/// an MPE member channel.
+	pub poly_pressure: Routing,
+	pub channel_pressure: Routing,
+	/// CC 74, per voice on MPE member channels.
+	pub slide: Routing,
+	/// The curve sets how note on velocity scales amplitude; the depths
+	/// send it on to the other destinations as well.
+	pub velocity: Routing,
+	pub release_velocity: Routing,
+	/// How the envelopes of a note that is still sounding respond to it
+	/// being struck again.
+	pub retrigger: Retrigger,
+	pub amp_env: Contour,
+	pub flt_env: Contour,
+	pub amp_env_scaling: TimeScaling,
+	pub flt_env_scaling: TimeScaling,
+	/// Shapes each voice on its way into the filter.
+	pub distortion: Distortion,
+	pub filter: Filter,
+	/// Shared by all voices; key synced, it restarts when the first note of
+	/// a phrase goes down.
+	pub lfo: Lfo,
+	pub voice_lfo: Lfo,
+	pub matrix: Matrix,
+	pub macros: [Macro; MACROS],
+}
+
+impl Patch {
+	pub fn new() -> Patch {
+		Patch {
+			bend_range: 2.0,
+			vibrato_rate: 5.5,
+			vibrato_depth: 0.,
+			wave_morph: Waveforms::Saw,
+			wave_position: 0.,
+			pulse_width: 0.5,
+			poly_pressure: Routing::new(),
+			channel_pressure: Routing::new(),
+			slide: Routing::new(),
+			velocity: Routing { curve: Curve::Linear, ..Routing::new() },
+			release_velocity: Routing::new(),
+			retrigger: Retrigger::Continue,
+			amp_env: Contour::new(),
+			flt_env: Contour::new(),
+			amp_env_scaling: TimeScaling::new(),
+			flt_env_scaling: TimeScaling::new(),
+			distortion: Distortion::new(),
+			filter: Filter::new(),
+			lfo: Lfo::new(),
+			voice_lfo: Lfo::new(),
+			matrix: Matrix::new(),
+			macros: [Macro::new(); MACROS],
+		}
+	}
+	/// A starting point for MPE controllers: pressure swells each voice
+	/// and slide morphs its waveform.
+	pub fn mpe() -> Patch {
+		Patch {
+			poly_pressure: Routing { amplitude: 0.5, ..Routing::new() },
+			slide: Routing { wave_position: 1.0, ..Routing::new() },
+			..Patch::new()
+		}
+	}
+	/// What the macro knobs add to every voice.
+	pub fn macro_modulation(&self) -> Modulation {
+		let mut mods = Modulation::new();
+		for knob in self.macros.iter() {
+			knob.apply(&mut mods);
+		}
+		mods
+	}
+}
+
+#[test]
+fn test_patch_serialization() {
+	use super::matrix::{Slot, Source, Destination};
+	use super::filter::{Kind, Mode};
+	let mut patch = Patch::new();
+	patch.filter.kind = Kind::Svf(Mode::Bandpass);
+	patch.distortion.shape = super::distortion::Shape::Fold;
+	patch.flt_env = Contour::mseg(&[super::envelope::Segment::new(0.5, 1.)], None, Some((0, 0)));
+	patch.matrix.add(Slot { via: Source::ModWheel, ..Slot::new(Source::Lfo, Destination::Cutoff, 12.) });
+	patch.matrix.add(Slot::new(Source::Cc(21), Destination::Pan, -1.));
+	patch.macros[2].cc = Some(22);
+	patch.macros[2].map(super::macros::Mapping::new(Destination::Resonance, 0.5, 0.));
+	let json = serde_json::to_string(&patch).unwrap();
+	let loaded: Patch = serde_json::from_str(&json).unwrap();
+	assert_eq!(loaded, patch);
+}
@@ -0,0 +1,182 @@
+use std::f64::consts::PI;
+
+use super::types::{SampleRated, SampleRate, Sample, Frequency};
+use super::effects::{Effect, Param, Sweep, clamp};
+
+pub const MAX_STAGES: usize = 12;
+
+const PARAMS: [Param; 8] = [
+	Param { name: "rate", min: 0.01, max: 10., default: 0.5 },
+	Param { name: "sync", min: 0., max: 1., default: 0. },
+	Param { name: "division", min: 0., max: 11., default: 0. },
+	Param { name: "stages", min: 4., max: MAX_STAGES as f64, default: 6. },
+	Param { name: "frequency", min: 100., max: 4000., default: 800. },
+	Param { name: "depth", min: 0., max: 1., default: 0.7 },
+	Param { name: "feedback", min: -0.9, max: 0.9, default: 0.3 },
+	Param { name: "spread", min: 0., max: 1., default: 0.25 },
+];
+
+/// Stereo phaser: a chain of first order allpass filters, which the
+/// `Chain` mixes back with the input, putting a notch wherever their phase
+/// shift reaches a half cycle, one for every two stages. The notches are
+/// deepest at a mix of 0.5. The allpasses are swept up to two octaves
+/// either side of `frequency`, with `spread` setting the right side up to
+/// half a cycle apart from the left.
+pub struct Phaser {
+	sweep: Sweep,
+	stages: usize,
+	frequency: Frequency,
+	depth: f64,
+	feedback: Sample,
+	spread: f64,
+	state: [[Sample; MAX_STAGES]; 2],
+	last: [Sample; 2],
+	sample_rate: Frequency,
+}
+
+impl Phaser {
+	pub fn new() -> Phaser {
+		let mut sweep = Sweep::new(PARAMS[0].default);
+		sweep.division = PARAMS[2].default as usize;
+		Phaser {
+			sweep,
+			stages: PARAMS[3].default as usize,
+			frequency: PARAMS[4].default,
+			depth: PARAMS[5].default,
+			feedback: PARAMS[6].default,
+			spread: PARAMS[7].default,
+			state: [[0.; MAX_STAGES]; 2],
+			last: [0.; 2],
+			sample_rate: 0.,
+		}
+	}
+}
+
+impl SampleRated for Phaser {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Frequency;
+		self.sweep.set_sample_rate(sample_rate);
+	}
+}
+
+impl Effect for Phaser {
+	fn name(&self) -> &'static str {
+		"Phaser"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		if self.sample_rate == 0. {
+			return [0.; 2];
+		}
+		let phase = self.sweep.run(self.sweep.frequency());
+		let mut out = [0.; 2];
+		for c in 0..2 {
+			let swing = (2. * PI * (phase + c as f64 * self.spread / 2.)).sin();
+			let freq = (self.frequency * (2.0 as f64).powf(2. * self.depth * swing)).min(0.45 * self.sample_rate);
+			let t = (PI * freq / self.sample_rate).tan();
+			let a = (t - 1.) / (t + 1.);
+			let mut y = input[c] + self.last[c] * self.feedback;
+			for s in self.state[c][..self.stages].iter_mut() {
+				let x = y;
+				y = a * x + *s;
+				*s = x - a * y;
+			}
+			self.last[c] = y;
+			out[c] = y;
+		}
+		out
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0..=2 => self.sweep.get(param),
+			3 => self.stages as f64,
+			4 => self.frequency,
+			5 => self.depth,
+			6 => self.feedback,
+			7 => self.spread,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		let value = clamp(&PARAMS, param, value);
+		match param {
+			0..=2 => self.sweep.set(param, value),
+			3 => self.stages = value.round() as usize,
+			4 => self.frequency = value,
+			5 => self.depth = value,
+			6 => self.feedback = value,
+			_ => self.spread = value,
+		}
+	}
+	fn reset(&mut self) {
+		self.state = [[0.; MAX_STAGES]; 2];
+		self.last = [0.; 2];
+		self.sweep.reset();
+	}
+	fn set_bpm(&mut self, bpm: f64) {
+		self.sweep.bpm = bpm;
+	}
+}
+
+/// Level a sine at `freq` comes through at, once settled, mixed half and
+/// half with the dry signal as in a `Chain`.
+#[cfg(test)]
+fn response(phaser: &mut Phaser, freq: Frequency) -> Sample {
+	phaser.reset();
+	let mut peak: Sample = 0.;
+	for i in 0..4800 {
+		let x = (2. * PI * freq * i as f64 / 48000.).sin();
+		let y = (x + phaser.process([x; 2])[0]) / 2.;
+		if i > 2400 {
+			peak = peak.max(y.abs());
+		}
+	}
+	peak
+}
+
+#[test]
+fn test_phaser_notches() {
+	let mut phaser = Phaser::new();
+	phaser.set(phaser.find("depth").unwrap(), 0.);
+	phaser.set(phaser.find("feedback").unwrap(), 0.);
+	phaser.set_sample_rate(48000);
+	// a notch for every two stages, counted across a log sweep
+	for &stages in &[4, 8, 12] {
+		phaser.set(phaser.find("stages").unwrap(), stages as f64);
+		let levels: Vec<_> = (0..120)
+			.map(|i| response(&mut phaser, 50. * (2.0 as f64).powf(i as f64 / 14.)))
+			.collect();
+		let notches = levels.windows(3).filter(|w| w[1] < w[0] && w[1] < w[2] && w[1] < 0.2).count();
+		assert_eq!(notches, stages / 2, "{} stages", stages);
+		assert!(levels.iter().any(|&level| level > 0.95));
+	}
+}
+
+#[test]
+fn test_phaser_sweep() {
+	let mut phaser = Phaser::new();
+	phaser.set(phaser.find("stages").unwrap(), 4.);
+	phaser.set(phaser.find("depth").unwrap(), 1.);
+	phaser.set(phaser.find("feedback").unwrap(), 0.9);
+	phaser.set(phaser.find("sync").unwrap(), 1.);
+	phaser.set(phaser.find("division").unwrap(), 3.);
+	phaser.set_sample_rate(48000);
+	phaser.set_bpm(120.);
+	assert_eq!(phaser.sweep.frequency(), 4.);
+	let mut peak: [Sample; 2] = [0.; 2];
+	let mut differ = false;
+	for i in 0..48000 {
+		let x = if i % 97 == 0 { 1. } else { 0. };
+		let [l, r] = phaser.process([x; 2]);
+		assert!(l.is_finite() && r.is_finite());
+		peak = [peak[0].max(l.abs()), peak[1].max(r.abs())];
+		differ |= l != r;
+	}
+	assert!(peak[0] < 10. && peak[1] < 10.);
+	assert!(differ);
+}
@@ -0,0 +1,289 @@
+use std::f64::consts::PI;
+
+use super::types::{SampleRated, SampleRate, Sample, Seconds};
+use super::effects::{Effect, Param, clamp};
+use super::delay::Line;
+use super::smoother::Smoother;
+
+/// Feedback delay lines in the tank, a power of two for the Hadamard mix.
+const LINES: usize = 8;
+const DIFFUSERS: usize = 4;
+
+/// Tank line lengths in seconds, spread so that their echoes don't line up.
+const LENGTHS: [Seconds; LINES] = [0.0297, 0.0371, 0.0411, 0.0437, 0.0473, 0.0539, 0.0593, 0.0671];
+const DIFFUSER_LENGTHS: [Seconds; DIFFUSERS] = [0.00477, 0.00359, 0.01273, 0.00931];
+
+/// Size 0 to 1 scales the tank from `MIN_SCALE` to `MAX_SCALE` times the
+/// lengths above.
+const MIN_SCALE: f64 = 0.4;
+const MAX_SCALE: f64 = 2.;
+
+const MAX_PRE_DELAY: Seconds = 0.5;
+/// Line length swing at full modulation, and how fast it swings.
+const MOD_DEPTH: Seconds = 0.001;
+const MOD_RATE: f64 = 0.7;
+
+const PARAMS: [Param; 7] = [
+	Param { name: "pre_delay", min: 0., max: MAX_PRE_DELAY * 1000., default: 20. },
+	Param { name: "size", min: 0., max: 1., default: 0.5 },
+	Param { name: "decay", min: 0.1, max: 30., default: 2. },
+	Param { name: "damping", min: 0., max: 1., default: 0.5 },
+	Param { name: "diffusion", min: 0., max: 1., default: 0.7 },
+	Param { name: "modulation", min: 0., max: 1., default: 0.2 },
+	Param { name: "width", min: 0., max: 1., default: 1. },
+];
+
+/// Stereo feedback delay network reverb. Each side is pre-delayed and
+/// smeared by a chain of allpass diffusers, then fed to half of the tank
+/// lines, which are mixed into each other through a Hadamard matrix and
+/// damped by a lowpass in every loop. `decay` is the time taken to fall by
+/// 60 dB. Gently swinging the line lengths with `modulation` breaks up
+/// metallic ringing, and `width` runs from mono to the full spread of the
+/// tank.
+pub struct Reverb {
+	pre_delay: Seconds,
+	size: f64,
+	decay: Seconds,
+	damping: Sample,
+	diffusion: Sample,
+	modulation: f64,
+	width: Sample,
+	pre_delays: Vec<Line>,
+	diffusers: Vec<Line>,
+	lines: Vec<Line>,
+	lowpass: [Sample; LINES],
+	scale: Smoother,
+	phase: f64,
+	sample_rate: f64,
+}
+
+impl Reverb {
+	pub fn new() -> Reverb {
+		Reverb {
+			pre_delay: PARAMS[0].default / 1000.,
+			size: PARAMS[1].default,
+			decay: PARAMS[2].default,
+			damping: PARAMS[3].default,
+			diffusion: PARAMS[4].default,
+			modulation: PARAMS[5].default,
+			width: PARAMS[6].default,
+			pre_delays: (0..2).map(|_| Line::new()).collect(),
+			diffusers: (0..2 * DIFFUSERS).map(|_| Line::new()).collect(),
+			lines: (0..LINES).map(|_| Line::new()).collect(),
+			lowpass: [0.; LINES],
+			scale: Smoother::new(0.1),
+			phase: 0.,
+			sample_rate: 0.,
+		}
+	}
+	fn scale(size: f64) -> f64 {
+		MIN_SCALE + (MAX_SCALE - MIN_SCALE) * size
+	}
+	fn samples(&self, time: Seconds) -> f64 {
+		time * self.sample_rate
+	}
+}
+
+/// In place, scaled to keep the energy of the lines.
+fn hadamard(x: &mut [Sample; LINES]) {
+	let mut h = 1;
+	while h < LINES {
+		for i in (0..LINES).step_by(2 * h) {
+			for j in i..i + h {
+				let (a, b) = (x[j], x[j + h]);
+				x[j] = a + b;
+				x[j + h] = a - b;
+			}
+		}
+		h *= 2;
+	}
+	let norm = 1. / (LINES as f64).sqrt();
+	for v in x.iter_mut() {
+		*v *= norm;
+	}
+}
+
+/// Schroeder allpass: smears the input in time without colouring it.
+fn allpass(line: &mut Line, delay: f64, gain: Sample, x: Sample) -> Sample {
+	let delayed = line.read(delay);
+	let w = x + gain * delayed;
+	line.write(w);
+	delayed - gain * w
+}
+
+impl SampleRated for Reverb {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as f64;
+		let pre_delay = self.samples(MAX_PRE_DELAY).ceil() as usize + 2;
+		for line in self.pre_delays.iter_mut() {
+			line.resize(pre_delay);
+		}
+		for (i, line) in self.diffusers.iter_mut().enumerate() {
+			line.resize((DIFFUSER_LENGTHS[i % DIFFUSERS] * self.sample_rate).ceil() as usize + 2);
+		}
+		for (line, length) in self.lines.iter_mut().zip(LENGTHS.iter()) {
+			let longest = length * MAX_SCALE + MOD_DEPTH;
+			line.resize((longest * self.sample_rate).ceil() as usize + 2);
+		}
+		self.lowpass = [0.; LINES];
+		self.scale.set_sample_rate(sample_rate);
+		self.scale.reset(Reverb::scale(self.size));
+	}
+}
+
+impl Effect for Reverb {
+	fn name(&self) -> &'static str {
+		"Reverb"
+	}
+	fn process(&mut self, input: [Sample; 2]) -> [Sample; 2] {
+		if self.lines[0].is_empty() {
+			return [0.; 2];
+		}
+		let mut diffused = [0.; 2];
+		let pre_delay = self.samples(self.pre_delay).max(1.);
+		let gain = 0.75 * self.diffusion;
+		for c in 0..2 {
+			let x = self.pre_delays[c].read(pre_delay);
+			self.pre_delays[c].write(input[c]);
+			diffused[c] = (0..DIFFUSERS).fold(x, |x, d| {
+				let delay = self.samples(DIFFUSER_LENGTHS[d]);
+				allpass(&mut self.diffusers[c * DIFFUSERS + d], delay, gain, x)
+			});
+		}
+
+		let scale = self.scale.run();
+		let depth = self.samples(MOD_DEPTH) * self.modulation;
+		let mut tank = [0.; LINES];
+		for (i, line) in self.lines.iter().enumerate() {
+			let swing = (2. * PI * (self.phase + i as f64 / LINES as f64)).sin();
+			let delay = self.samples(LENGTHS[i] * scale) + depth * (1. + swing) / 2.;
+			tank[i] = line.read(delay.max(1.));
+		}
+		self.phase += MOD_RATE / self.sample_rate;
+		self.phase -= self.phase.floor();
+
+		// even lines make up the left, odd the right
+		let mut out = [0.; 2];
+		for (i, v) in tank.iter().enumerate() {
+			out[i % 2] += v / (LINES as f64 / 2.).sqrt();
+		}
+
+		hadamard(&mut tank);
+		for (i, line) in self.lines.iter_mut().enumerate() {
+			let time = LENGTHS[i] * scale;
+			let feedback = (10.0 as f64).powf(-3. * time / self.decay);
+			let lowpass = &mut self.lowpass[i];
+			*lowpass = tank[i] * (1. - 0.9 * self.damping) + *lowpass * 0.9 * self.damping;
+			line.write(*lowpass * feedback + diffused[i % 2]);
+		}
+
+		let mid = (out[0] + out[1]) / 2.;
+		let side = (out[0] - out[1]) / 2. * self.width;
+		[mid + side, mid - side]
+	}
+	fn params(&self) -> &'static [Param] {
+		&PARAMS
+	}
+	fn get(&self, param: usize) -> f64 {
+		match param {
+			0 => self.pre_delay * 1000.,
+			1 => self.size,
+			2 => self.decay,
+			3 => self.damping,
+			4 => self.diffusion,
+			5 => self.modulation,
+			6 => self.width,
+			_ => 0.,
+		}
+	}
+	fn set(&mut self, param: usize, value: f64) {
+		if param >= PARAMS.len() {
+			return;
+		}
+		let value = clamp(&PARAMS, param, value);
+		match param {
+			0 => self.pre_delay = value / 1000.,
+			1 => {
+				self.size = value;
+				self.scale.set(Reverb::scale(value));
+			},
+			2 => self.decay = value,
+			3 => self.damping = value,
+			4 => self.diffusion = value,
+			5 => self.modulation = value,
+			_ => self.width = value,
+		}
+	}
+	fn reset(&mut self) {
+		for line in self.pre_delays.iter_mut().chain(self.diffusers.iter_mut()).chain(self.lines.iter_mut()) {
+			line.clear();
+		}
+		self.lowpass = [0.; LINES];
+	}
+}
+
+#[cfg(test)]
+fn energy(out: &[[Sample; 2]]) -> Sample {
+	out.iter().map(|s| s[0] * s[0] + s[1] * s[1]).sum::<Sample>() / out.len() as Sample
+}
+
+#[test]
+fn test_reverb_decay() {
+	let mut reverb = Reverb::new();
+	reverb.set(reverb.find("pre_delay").unwrap(), 50.);
+	reverb.set(reverb.find("decay").unwrap(), 1.);
+	reverb.set(reverb.find("damping").unwrap(), 0.);
+	reverb.set(reverb.find("modulation").unwrap(), 0.);
+	reverb.set_sample_rate(48000);
+	let out: Vec<_> = (0..96000).map(|i| reverb.process(if i == 0 { [1., 1.] } else { [0.; 2] })).collect();
+
+	// silent through the pre-delay
+	assert!(out[..2400].iter().all(|s| s == &[0.; 2]));
+	assert!(out[2400..12000].iter().any(|s| s[0] != 0.));
+
+	// down by about 60 dB a second on
+	let early = energy(&out[12000..24000]);
+	let late = energy(&out[60000..72000]);
+	let db = 10. * (late / early).log10();
+	assert!(db < -50. && db > -70., "{} dB", db);
+
+	// damping takes the highs off, so a brighter impulse fades faster
+	reverb.reset();
+	reverb.set(reverb.find("damping").unwrap(), 1.);
+	let damped: Vec<_> = (0..72000).map(|i| reverb.process(if i == 0 { [1., 1.] } else { [0.; 2] })).collect();
+	assert!(energy(&damped[60000..72000]) < late);
+}
+
+#[test]
+fn test_reverb_width() {
+	let mut reverb = Reverb::new();
+	reverb.set_sample_rate(48000);
+	reverb.set(reverb.find("width").unwrap(), 0.);
+	for i in 0..4800 {
+		let [l, r] = reverb.process([if i == 0 { 1. } else { 0. }, 0.]);
+		assert!((l - r).abs() < 1e-12);
+	}
+	reverb.reset();
+	reverb.set(reverb.find("width").unwrap(), 1.);
+	let out: Vec<_> = (0..4800).map(|i| reverb.process([if i == 0 { 1. } else { 0. }, 0.])).collect();
+	assert!(out.iter().any(|s| (s[0] - s[1]).abs() > 1e-3));
+}
+
+#[test]
+fn test_reverb_stable() {
+	let mut reverb = Reverb::new();
+	for (param, value) in &[("decay", 30.), ("damping", 0.), ("diffusion", 1.), ("modulation", 1.), ("size", 0.)] {
+		reverb.set(reverb.find(param).unwrap(), *value);
+	}
+	reverb.set_sample_rate(48000);
+	// a full scale square wave, with the size swept as it plays
+	for i in 0..96000 {
+		if i == 48000 {
+			reverb.set(reverb.find("size").unwrap(), 1.);
+		}
+		let x = if i % 200 < 100 { 1. } else { -1. };
+		let [l, r] = reverb.process([x, x]);
+		assert!(l.is_finite() && r.is_finite());
+		assert!(l.abs() < 100. && r.abs() < 100., "{} {}", l, r);
+	}
+}
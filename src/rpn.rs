@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+pub const DATA_ENTRY_MSB: u8 = 6;
+pub const DATA_ENTRY_LSB: u8 = 38;
+pub const DATA_INCREMENT: u8 = 96;
+pub const DATA_DECREMENT: u8 = 97;
+pub const NRPN_LSB: u8 = 98;
+pub const NRPN_MSB: u8 = 99;
+pub const RPN_LSB: u8 = 100;
+pub const RPN_MSB: u8 = 101;
+
+pub const RPN_BEND_RANGE: u16 = 0;
+pub const RPN_FINE_TUNING: u16 = 1;
+pub const RPN_COARSE_TUNING: u16 = 2;
+pub const RPN_TUNING_PROGRAM: u16 = 3;
+pub const RPN_TUNING_BANK: u16 = 4;
+const RPN_NULL: u16 = 0x3fff;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Parameter {
+	Rpn(u16),
+	Nrpn(u16),
+}
+
+/// Tracks which registered or non-registered parameter a channel has
+/// selected, and the last value written to each one, so that data entry
+/// LSB and increment/decrement messages have something to apply to.
+#[derive(Clone, Debug)]
+pub struct ParameterState {
+	msb: u8,
+	lsb: u8,
+	selected: Option<Parameter>,
+	values: HashMap<Parameter, u16>,
+}
+
+impl ParameterState {
+	pub fn new() -> ParameterState {
+		ParameterState {
+			msb: 0x7f,
+			lsb: 0x7f,
+			selected: None,
+			values: HashMap::new(),
+		}
+	}
+	pub fn selected(&self) -> Option<Parameter> {
+		self.selected
+	}
+	fn select(&mut self, rpn: bool) {
+		let number = ((self.msb as u16) << 7) | self.lsb as u16;
+		self.selected = if number == RPN_NULL {
+			None
+		} else if rpn {
+			Some(Parameter::Rpn(number))
+		} else {
+			Some(Parameter::Nrpn(number))
+		};
+	}
+	/// Parameters whose value lives entirely in the data entry MSB step by
+	/// a whole MSB on increment/decrement.
+	fn step(param: Parameter) -> u16 {
+		match param {
+			Parameter::Rpn(RPN_COARSE_TUNING) |
+			Parameter::Rpn(RPN_TUNING_PROGRAM) |
+			Parameter::Rpn(RPN_TUNING_BANK) => 1 << 7,
+			_ => 1,
+		}
+	}
+	/// Feeds a control change through the state machine. Returns the
+	/// parameter and its new 14-bit value when the message changed one.
+	pub fn control_change(&mut self, control: u8, value: u8) -> Option<(Parameter, u16)> {
+		match control {
+			RPN_MSB  => { self.msb = value; self.select(true);  None },
+			RPN_LSB  => { self.lsb = value; self.select(true);  None },
+			NRPN_MSB => { self.msb = value; self.select(false); None },
+			NRPN_LSB => { self.lsb = value; self.select(false); None },
+			DATA_ENTRY_MSB | DATA_ENTRY_LSB | DATA_INCREMENT | DATA_DECREMENT => {
+				let param = self.selected?;
+				let old = *self.values.get(&param).unwrap_or(&0);
+				let new = match control {
+					DATA_ENTRY_MSB => (value as u16) << 7,
+					DATA_ENTRY_LSB => (old & !0x7f) | value as u16,
+					DATA_INCREMENT => (old + Self::step(param)).min(RPN_NULL),
+					_              => old.saturating_sub(Self::step(param)),
+				};
+				self.values.insert(param, new);
+				Some((param, new))
+			},
+			_ => None,
+		}
+	}
+}
+
+#[test]
+fn test_parameter_state() {
+	let mut state = ParameterState::new();
+	assert_eq!(state.control_change(DATA_ENTRY_MSB, 12), None);
+
+	state.control_change(RPN_MSB, 0);
+	state.control_change(RPN_LSB, 0);
+	assert_eq!(state.selected(), Some(Parameter::Rpn(RPN_BEND_RANGE)));
+	assert_eq!(state.control_change(DATA_ENTRY_MSB, 12), Some((Parameter::Rpn(0), 12 << 7)));
+	assert_eq!(state.control_change(DATA_ENTRY_LSB, 50), Some((Parameter::Rpn(0), (12 << 7) | 50)));
+	assert_eq!(state.control_change(DATA_INCREMENT, 0), Some((Parameter::Rpn(0), (12 << 7) | 51)));
+
+	state.control_change(RPN_LSB, 2);
+	assert_eq!(state.control_change(DATA_ENTRY_MSB, 64), Some((Parameter::Rpn(2), 64 << 7)));
+	assert_eq!(state.control_change(DATA_DECREMENT, 0), Some((Parameter::Rpn(2), 63 << 7)));
+
+	state.control_change(NRPN_MSB, 1);
+	state.control_change(NRPN_LSB, 8);
+	assert_eq!(state.control_change(DATA_ENTRY_MSB, 3), Some((Parameter::Nrpn(136), 3 << 7)));
+
+	state.control_change(RPN_MSB, 0x7f);
+	state.control_change(RPN_LSB, 0x7f);
+	assert_eq!(state.selected(), None);
+	assert_eq!(state.control_change(DATA_INCREMENT, 0), None);
+}
@@ -0,0 +1,79 @@
+use super::types::{SampleRated, SampleRate, Sample, Seconds};
+
+/// One-pole parameter smoother, used to take the zipper noise out of
+/// controller-driven values such as pitch bend.
+#[derive(Clone, Copy, Debug)]
+pub struct Smoother {
+	val: Sample,
+	target: Sample,
+	time: Seconds,
+	coef: Sample,
+	sample_rate: Sample,
+}
+
+impl SampleRated for Smoother {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate as Sample;
+		self.calc();
+	}
+}
+
+impl Smoother {
+	pub fn new(time: Seconds) -> Smoother {
+		Smoother {
+			val: 0.,
+			target: 0.,
+			time: time,
+			coef: 0.,
+			sample_rate: 0.,
+		}
+	}
+	fn calc(&mut self) {
+		if self.time > 0. {
+			self.coef = (-1. / (self.time * self.sample_rate)).exp();
+		} else {
+			self.coef = 0.;
+		}
+	}
+	pub fn set_time(&mut self, time: Seconds) {
+		self.time = time;
+		self.calc();
+	}
+	pub fn set(&mut self, target: Sample) {
+		self.target = target;
+	}
+	pub fn reset(&mut self, val: Sample) {
+		self.val = val;
+		self.target = val;
+	}
+	pub fn value(&self) -> Sample {
+		self.val
+	}
+	pub fn target(&self) -> Sample {
+		self.target
+	}
+	pub fn is_settled(&self) -> bool {
+		self.val == self.target
+	}
+	pub fn run(&mut self) -> Sample {
+		if self.val != self.target {
+			self.val = self.target + (self.val - self.target) * self.coef;
+			if (self.val - self.target).abs() < 1e-9 {
+				self.val = self.target;
+			}
+		}
+		self.val
+	}
+}
+
+#[test]
+fn test_smoother() {
+	let mut s = Smoother::new(0.005);
+	s.set_sample_rate(48000);
+	s.set(1.);
+	let v = s.run();
+	assert!(v > 0. && v < 0.01, "first step should be small, got {}", v);
+	for _ in 0..48000 { s.run(); }
+	assert!(s.is_settled());
+	assert_eq!(s.value(), 1.);
+}
@@ -1,7 +1,7 @@
 use std::fmt;
 use super::types::{Cents, Frequency};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Tuning {
 	EquaTemp,
 	MeanTemp,
@@ -15,6 +15,25 @@ pub enum Tuning {
 	Kirnberger,
 }
 
+impl Tuning {
+	pub const ALL: [Tuning; 10] = [
+		Tuning::EquaTemp,
+		Tuning::MeanTemp,
+		Tuning::Just5Temp,
+		Tuning::KeplTemp,
+		Tuning::PythTemp,
+		Tuning::HammTemp,
+		Tuning::PtolTemp,
+		Tuning::ChinTemp,
+		Tuning::Dowland,
+		Tuning::Kirnberger,
+	];
+	/// Maps a MIDI tuning program number (RPN 3) onto a preset.
+	pub fn from_program(program: u8) -> Option<Tuning> {
+		Self::ALL.get(program as usize).copied()
+	}
+}
+
 trait OctaveTuning {
 	fn init_octave() -> [Frequency; 12];
 }
@@ -0,0 +1,120 @@
+use serde::{Serialize, Deserialize};
+
+use super::types::{Frequency, Seconds};
+
+pub const CLOCKS_PER_BEAT: f64 = 24.;
+
+/// A note length for tempo-synced rates, counted in quarter note beats.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Division {
+	Whole,
+	Half,
+	Quarter,
+	Eighth,
+	Sixteenth,
+	ThirtySecond,
+	DottedQuarter,
+	DottedEighth,
+	DottedSixteenth,
+	QuarterTriplet,
+	EighthTriplet,
+	SixteenthTriplet,
+}
+
+/// Every division, in the order of the enum, for choosing one by number.
+pub const DIVISIONS: [Division; 12] = [
+	Division::Whole,
+	Division::Half,
+	Division::Quarter,
+	Division::Eighth,
+	Division::Sixteenth,
+	Division::ThirtySecond,
+	Division::DottedQuarter,
+	Division::DottedEighth,
+	Division::DottedSixteenth,
+	Division::QuarterTriplet,
+	Division::EighthTriplet,
+	Division::SixteenthTriplet,
+];
+
+impl Division {
+	pub fn beats(&self) -> f64 {
+		use Division::*;
+		match self {
+			Whole            => 4.,
+			Half             => 2.,
+			Quarter          => 1.,
+			Eighth           => 1. / 2.,
+			Sixteenth        => 1. / 4.,
+			ThirtySecond     => 1. / 8.,
+			DottedQuarter    => 3. / 2.,
+			DottedEighth     => 3. / 4.,
+			DottedSixteenth  => 3. / 8.,
+			QuarterTriplet   => 2. / 3.,
+			EighthTriplet    => 1. / 3.,
+			SixteenthTriplet => 1. / 6.,
+		}
+	}
+	pub fn seconds(&self, bpm: f64) -> Seconds {
+		self.beats() * 60. / bpm
+	}
+	pub fn frequency(&self, bpm: f64) -> Frequency {
+		1. / self.seconds(bpm)
+	}
+}
+
+const TICKS: usize = CLOCKS_PER_BEAT as usize + 1;
+
+/// Estimates the tempo from MIDI clock, timed in samples. It goes by the
+/// last beat's worth of ticks, to smooth over their arriving in bursts,
+/// one for each audio buffer.
+pub struct ClockFollower {
+	ticks: [u64; TICKS],
+	count: usize,
+}
+
+impl ClockFollower {
+	pub fn new() -> ClockFollower {
+		ClockFollower { ticks: [0; TICKS], count: 0 }
+	}
+	/// Forgets the ticks so far, for when the clock starts again.
+	pub fn reset(&mut self) {
+		self.count = 0;
+	}
+	/// Notes a tick at sample `clk`, returning the tempo once there are
+	/// two to go by.
+	pub fn tick(&mut self, clk: u64, sample_rate: f64) -> Option<f64> {
+		self.ticks[self.count % TICKS] = clk;
+		self.count += 1;
+		let span = (self.count - 1).min(TICKS - 1);
+		let first = self.ticks[(self.count - 1 - span) % TICKS];
+		if span == 0 || clk <= first {
+			return None;
+		}
+		Some(60. * sample_rate * span as f64 / (CLOCKS_PER_BEAT * (clk - first) as f64))
+	}
+}
+
+#[test]
+fn test_division() {
+	assert_eq!(Division::Quarter.seconds(120.), 0.5);
+	assert_eq!(Division::Sixteenth.frequency(120.), 8.);
+	assert_eq!(Division::DottedEighth.beats() * CLOCKS_PER_BEAT, 18.);
+	assert_eq!(Division::SixteenthTriplet.beats() * CLOCKS_PER_BEAT, 4.);
+}
+
+#[test]
+fn test_clock_follower() {
+	let mut follower = ClockFollower::new();
+	assert_eq!(follower.tick(0, 1000.), None);
+	// a tick every 25 ms is a beat every 0.6 s
+	assert_eq!(follower.tick(25, 1000.), Some(100.));
+	for clk in 2..48 {
+		follower.tick(clk * 25, 1000.);
+	}
+	// bunched up in 100 sample buffers, still 100 bpm over the beat
+	let bpm = (48..96).filter_map(|tick| follower.tick(tick * 25 / 100 * 100, 1000.)).last();
+	assert!((bpm.unwrap() - 100.).abs() < 5.);
+	follower.reset();
+	assert_eq!(follower.tick(5000, 1000.), None);
+}
@@ -1,6 +1,7 @@
 
 pub type Frequency = f64;
 pub type Cents = f64;
+pub type Semitones = f64;
 pub type Sample = f64;
 pub type SampleRate = u32;
 pub type Seconds = f64;
@@ -16,3 +17,8 @@ pub trait Generator {
 pub trait SampleRated {
 	fn set_sample_rate(&mut self, sample_rate: SampleRate);
 }
+
+/// Takes the tempo, for whatever is synced to note divisions.
+pub trait Tempo {
+	fn set_bpm(&mut self, _bpm: f64) {}
+}
@@ -0,0 +1,187 @@
+use super::types::{SampleRated, Generator, SampleRate, Sample, MidiDispatcher};
+use super::oscillator::{Oscillator, Waveforms};
+
+/// A key and velocity range with its own oscillator, and so its own patch
+/// and tuning. Notes are transposed before they reach the oscillator.
+pub struct Zone {
+	pub lo_key: u8, pub hi_key: u8,
+	pub lo_vel: u8, pub hi_vel: u8,
+	pub transpose: i8,
+	pub osc: Oscillator,
+	/// The note each held key was transposed to when it went down.
+	held: [Option<u8>; 128],
+}
+
+impl Zone {
+	pub fn new(lo_key: u8, hi_key: u8, waveform: Waveforms) -> Zone {
+		Zone {
+			lo_key: lo_key, hi_key: hi_key,
+			lo_vel: 1, hi_vel: 127,
+			transpose: 0,
+			osc: Oscillator::new(waveform),
+			held: [None; 128],
+		}
+	}
+	fn accepts(&self, key: u8, vel: u8) -> bool {
+		key >= self.lo_key && key <= self.hi_key &&
+		vel >= self.lo_vel && vel <= self.hi_vel
+	}
+	fn transposed(&self, key: u8) -> Option<u8> {
+		let n = key as i16 + self.transpose as i16;
+		if n < 0 || n > 127 { None } else { Some(n as u8) }
+	}
+}
+
+/// Splits and layers on a single channel: every zone whose ranges take a
+/// note plays it, so overlapping zones stack up as layers.
+pub struct Keyboard {
+	zones: Vec<Zone>,
+	sample_rate: SampleRate,
+}
+
+impl SampleRated for Keyboard {
+	fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+		self.sample_rate = sample_rate;
+		for zone in self.zones.iter_mut() {
+			zone.osc.set_sample_rate(sample_rate);
+		}
+	}
+}
+
+impl Keyboard {
+	pub fn new() -> Keyboard {
+		Keyboard {
+			zones: vec![],
+			sample_rate: 0,
+		}
+	}
+	pub fn add_zone(&mut self, mut zone: Zone) -> usize {
+		if self.sample_rate > 0 {
+			zone.osc.set_sample_rate(self.sample_rate);
+		}
+		self.zones.push(zone);
+		self.zones.len() - 1
+	}
+	pub fn remove_zone(&mut self, index: usize) -> Zone {
+		self.zones.remove(index)
+	}
+	pub fn zone(&self, index: usize) -> &Zone {
+		&self.zones[index]
+	}
+	pub fn zone_mut(&mut self, index: usize) -> &mut Zone {
+		&mut self.zones[index]
+	}
+	pub fn zones(&self) -> &[Zone] {
+		&self.zones
+	}
+}
+
+impl Generator for Keyboard {
+	fn generate(&mut self) -> [f32; 2] {
+		let mut left: Sample = 0.;
+		let mut right: Sample = 0.;
+		for zone in self.zones.iter_mut() {
+			let out = zone.osc.generate();
+			left  += out[0] as Sample;
+			right += out[1] as Sample;
+		}
+		[left as f32, right as f32]
+	}
+}
+
+impl MidiDispatcher for Keyboard {
+	fn dispatch_midi_in(&mut self, msg: &midistream::Msg) {
+		use midistream::*;
+		match msg {
+			Msg::Simple(SimpleMsg::NoteOn(y)) if *y.value > 0 => {
+				for zone in self.zones.iter_mut() {
+					if !zone.accepts(*y.note, *y.value) {
+						continue;
+					}
+					if let Some(n) = zone.transposed(*y.note) {
+						// struck again after a change of transpose
+						if let Some(old) = zone.held[*y.note as usize].filter(|&old| old != n) {
+							zone.osc.dispatch_midi_in(&SimpleMsg::note_off(y.channel, old, 0).into());
+						}
+						zone.held[*y.note as usize] = Some(n);
+						zone.osc.dispatch_midi_in(&SimpleMsg::note_on(y.channel, n, y.value).into());
+					}
+				}
+			},
+			// note offs and poly pressure follow the note on, not the ranges
+			// or the transpose, so a velocity layer still releases when the
+			// key comes up
+			Msg::Simple(SimpleMsg::NoteOn(y)) |
+			Msg::Simple(SimpleMsg::NoteOff(y)) => {
+				let key = *y.note as usize;
+				for zone in self.zones.iter_mut() {
+					if let Some(n) = zone.held[key].take() {
+						zone.osc.dispatch_midi_in(&SimpleMsg::note_off(y.channel, n, y.value).into());
+					}
+				}
+			},
+			Msg::Simple(SimpleMsg::PolyKeyPressure(y)) => {
+				for zone in self.zones.iter_mut() {
+					if let Some(n) = zone.held[*y.note as usize] {
+						zone.osc.dispatch_midi_in(&SimpleMsg::poly_key_pressure(y.channel, n, y.value).into());
+					}
+				}
+			},
+			_ => {
+				for zone in self.zones.iter_mut() {
+					zone.osc.dispatch_midi_in(msg);
+				}
+			},
+		}
+	}
+}
+
+#[test]
+fn test_split_and_layer() {
+	use midistream::SimpleMsg;
+	let mut kbd = Keyboard::new();
+	let mut bass = Zone::new(0, 59, Waveforms::Saw);
+	bass.transpose = -12;
+	let bass = kbd.add_zone(bass);
+	let pad = kbd.add_zone(Zone::new(60, 127, Waveforms::Triangle));
+	let mut accent = Zone::new(60, 127, Waveforms::Square);
+	accent.lo_vel = 100;
+	let accent = kbd.add_zone(accent);
+	kbd.set_sample_rate(48000);
+
+	kbd.dispatch_midi_in(&SimpleMsg::note_on(0, 40, 80).into());
+	kbd.dispatch_midi_in(&SimpleMsg::note_on(0, 72, 80).into());
+	assert_eq!(kbd.zone(bass).osc.active_voices(), 1);
+	assert_eq!(kbd.zone(pad).osc.active_voices(), 1);
+	assert_eq!(kbd.zone(accent).osc.active_voices(), 0);
+
+	kbd.dispatch_midi_in(&SimpleMsg::note_on(0, 76, 110).into());
+	assert_eq!(kbd.zone(pad).osc.active_voices(), 2);
+	assert_eq!(kbd.zone(accent).osc.active_voices(), 1);
+
+	kbd.dispatch_midi_in(&SimpleMsg::note_off(0, 40, 0).into());
+	kbd.dispatch_midi_in(&SimpleMsg::note_on(0, 76, 0).into());
+	assert_eq!(kbd.zone(bass).osc.held_notes(), 0);
+	assert_eq!(kbd.zone(pad).osc.held_notes(), 1);
+	assert_eq!(kbd.zone(accent).osc.held_notes(), 0);
+	kbd.generate();
+}
+
+#[test]
+fn test_transpose_while_held() {
+	use midistream::SimpleMsg;
+	let mut kbd = Keyboard::new();
+	let bass = kbd.add_zone(Zone::new(0, 127, Waveforms::Saw));
+	kbd.set_sample_rate(48000);
+	kbd.dispatch_midi_in(&SimpleMsg::note_on(0, 120, 80).into());
+	// out of range for the note now held, which must still come up
+	kbd.zone_mut(bass).transpose = 12;
+	kbd.dispatch_midi_in(&SimpleMsg::poly_key_pressure(0, 120, 64).into());
+	kbd.dispatch_midi_in(&SimpleMsg::note_off(0, 120, 0).into());
+	assert_eq!(kbd.zone(bass).osc.held_notes(), 0);
+
+	kbd.dispatch_midi_in(&SimpleMsg::note_on(0, 60, 80).into());
+	kbd.zone_mut(bass).transpose = -12;
+	kbd.dispatch_midi_in(&SimpleMsg::note_off(0, 60, 0).into());
+	assert_eq!(kbd.zone(bass).osc.held_notes(), 0);
+}